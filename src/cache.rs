@@ -0,0 +1,64 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Local cache of the last good responses, restored on startup when apollo is unreachable.
+
+use crate::output::write_file_atomically;
+use apollo_client::{conf::responses::FetchResponse, utils::canonicalize_namespace};
+use serde::Deserialize;
+use std::{collections::HashMap, io, path::PathBuf};
+use tokio::fs;
+
+/// Cached namespace, stored as json.
+#[derive(Deserialize)]
+pub struct CachedNamespace {
+    /// Notification ids of the clusters.
+    pub notification_ids: HashMap<String, i32>,
+    pub response: FetchResponse,
+}
+
+/// Cache directory of an app.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub async fn new(dir: PathBuf) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir).await?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, namespace: &str) -> PathBuf {
+        let mut path = self.dir.clone();
+        path.push(format!("{}.json", canonicalize_namespace(namespace)));
+        path
+    }
+
+    /// Load the cached namespace, `None` if not cached.
+    pub async fn load(&self, namespace: &str) -> anyhow::Result<Option<CachedNamespace>> {
+        match fs::read(self.path(namespace)).await {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn store(
+        &self, notification_ids: &HashMap<String, i32>, response: &FetchResponse,
+    ) -> anyhow::Result<()> {
+        let content = serde_json::to_vec(&serde_json::json!({
+            "notification_ids": notification_ids,
+            "response": response,
+        }))?;
+        write_file_atomically(&self.path(&response.namespace_name), &content).await
+    }
+}
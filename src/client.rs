@@ -0,0 +1,405 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Client of the apollo config service apis, like [apollo_client::conf::ApolloConfClient], but
+//! the requests of the apps having access key are signed, and the config services can be
+//! discovered by the meta server.
+
+use apollo_client::{
+    conf::{
+        meta::Notification,
+        requests::{FetchRequest, NotifyRequest},
+        responses::FetchResponse,
+    },
+    errors::{ApolloClientError, ApolloClientResult, ApolloResponseError},
+};
+use futures_util::future;
+use hmac::{Hmac, Mac};
+use chrono::{DateTime, Local};
+use reqwest::StatusCode;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha1::Sha1;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::sleep;
+use url::Url;
+
+/// Timeout of the requests except the long polling.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interval of refreshing the config services from the meta server.
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Interval of retrying the discovery after failure.
+const DISCOVERY_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Config service instance returned by the meta server.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ServiceDto {
+    homepage_url: String,
+}
+
+/// Status of a config service, reported by the `/status` endpoint.
+#[derive(Clone, Serialize)]
+pub struct ConfigServiceStatus {
+    pub url: String,
+
+    /// Whether the requests are sent to this config service.
+    pub in_use: bool,
+
+    /// The last error occurred when the config service was unreachable.
+    pub last_error: Option<String>,
+
+    /// Time of the last error.
+    pub last_error_time: Option<DateTime<Local>>,
+}
+
+/// Config services and the one in use.
+#[derive(Default)]
+struct ConfigServices {
+    urls: Vec<Url>,
+    current: usize,
+    last_errors: HashMap<Url, (String, DateTime<Local>)>,
+}
+
+impl ConfigServices {
+    /// Replace the config services, keep using the current one if it's still there, otherwise
+    /// choose one randomly, so the load of the pullers is spread among the config services.
+    fn replace(&mut self, urls: Vec<Url>) {
+        let current = self.urls.get(self.current).cloned();
+        self.current = match current.and_then(|current| urls.iter().position(|url| *url == current))
+        {
+            Some(current) => current,
+            None => random_index(urls.len()),
+        };
+        self.last_errors.retain(|url, _| urls.contains(url));
+        self.urls = urls;
+
+        if let Some(url) = self.urls.get(self.current) {
+            log::info!("Using config service {}", url);
+        }
+    }
+
+    /// Record the error of the config service, switch to the next one if it's in use.
+    fn set_error(&mut self, url: &Url, e: &ApolloClientError) {
+        self.last_errors
+            .insert(url.clone(), (e.to_string(), Local::now()));
+
+        if self.urls.get(self.current) == Some(url) && self.urls.len() > 1 {
+            self.current = (self.current + 1) % self.urls.len();
+            log::info!("Switched to config service {}", self.urls[self.current]);
+        }
+    }
+}
+
+/// Apollo config service apis client.
+pub struct Client {
+    client: reqwest::Client,
+
+    /// Meta server discovering the config services, `None` if the config services are fixed.
+    meta_server_url: Option<Url>,
+
+    /// Config services, the requests are sent to the one in use, and fail over to the next one
+    /// if it is unreachable.
+    services: Mutex<ConfigServices>,
+
+    /// Access key secrets keyed by app id.
+    secrets: HashMap<String, String>,
+}
+
+impl Client {
+    /// Create a client requesting the fixed config services.
+    pub fn new(urls: Vec<Url>, secrets: HashMap<String, String>) -> anyhow::Result<Self> {
+        for url in &urls {
+            check_base_url(url)?;
+        }
+        Self::build(None, urls, secrets)
+    }
+
+    /// Create a client requesting the config services discovered by the meta server, the
+    /// services are empty until [Client::discover] is called.
+    pub fn with_meta_server(
+        meta_server_url: Url, secrets: HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        check_base_url(&meta_server_url)?;
+        Self::build(Some(meta_server_url), Vec::new(), secrets)
+    }
+
+    fn build(
+        meta_server_url: Option<Url>, urls: Vec<Url>, secrets: HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let mut services = ConfigServices::default();
+        services.replace(urls);
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(DEFAULT_TIMEOUT).build()?,
+            meta_server_url,
+            services: Mutex::new(services),
+            secrets,
+        })
+    }
+
+    /// Status of the config services.
+    pub fn status(&self) -> Vec<ConfigServiceStatus> {
+        let services = self.services.lock().unwrap();
+        services
+            .urls
+            .iter()
+            .enumerate()
+            .map(|(i, url)| {
+                let last_error = services.last_errors.get(url);
+                ConfigServiceStatus {
+                    url: url.to_string(),
+                    in_use: i == services.current,
+                    last_error: last_error.map(|(e, _)| e.clone()),
+                    last_error_time: last_error.map(|(_, time)| *time),
+                }
+            })
+            .collect()
+    }
+
+    /// Refresh the config services from the meta server, do nothing if the config services are
+    /// fixed.
+    pub async fn discover(&self) -> anyhow::Result<()> {
+        let meta_server_url = match &self.meta_server_url {
+            Some(meta_server_url) => meta_server_url,
+            None => return Ok(()),
+        };
+
+        let mut url = meta_server_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| ApolloClientError::UrlCannotBeABase)?
+            .pop_if_empty()
+            .extend(&["services", "config"]);
+
+        let response = self.client.get(url).send().await?.error_for_status()?;
+        let services = response.json::<Vec<ServiceDto>>().await?;
+        let mut urls = services
+            .iter()
+            .map(|service| Url::parse(&service.homepage_url))
+            .collect::<Result<Vec<_>, _>>()?;
+        urls.sort();
+        if urls.is_empty() {
+            anyhow::bail!("no config service found by meta server {}", meta_server_url);
+        }
+
+        let mut services = self.services.lock().unwrap();
+        if services.urls != urls {
+            log::info!(
+                "Discovered config services: {}",
+                urls.iter().map(Url::as_str).collect::<Vec<_>>().join(", ")
+            );
+            services.replace(urls);
+        }
+        Ok(())
+    }
+
+    /// Refresh the config services from the meta server periodically, never return.
+    pub async fn keep_discovering(&self) {
+        if self.meta_server_url.is_none() {
+            return future::pending().await;
+        }
+        loop {
+            let interval = if self.services.lock().unwrap().urls.is_empty() {
+                DISCOVERY_RETRY_INTERVAL
+            } else {
+                DISCOVERY_INTERVAL
+            };
+            sleep(interval).await;
+            if let Err(e) = self.discover().await {
+                log::error!("Discover config services failed: {:?}", e);
+            }
+        }
+    }
+
+    /// Fetch the configurations without cache.
+    pub async fn fetch(&self, request: FetchRequest) -> ApolloClientResult<FetchResponse> {
+        let mut queries = Vec::new();
+        if let Some(ip) = &request.ip {
+            queries.push(("ip".to_string(), ip.to_string()));
+        }
+        if let Some(release_key) = &request.release_key {
+            queries.push(("releaseKey".to_string(), release_key.clone()));
+        }
+        queries.extend(request.extras_queries.iter().cloned());
+
+        self.get(
+            &request.app_id,
+            &[
+                "configs",
+                &request.app_id,
+                &request.cluster_name,
+                &request.namespace_name,
+            ],
+            &queries,
+            DEFAULT_TIMEOUT,
+        )
+        .await
+    }
+
+    /// Long poll the notifications of the namespaces, [NotifyRequest] has no extra queries, so
+    /// they are passed separately.
+    pub async fn notify(
+        &self, request: NotifyRequest, extras_queries: &[(String, String)],
+    ) -> ApolloClientResult<Vec<Notification>> {
+        let mut queries = vec![
+            ("appId".to_string(), request.app_id.clone()),
+            ("cluster".to_string(), request.cluster_name.clone()),
+            (
+                "notifications".to_string(),
+                serde_json::to_string(&request.notifications)?,
+            ),
+        ];
+        queries.extend(extras_queries.iter().cloned());
+
+        self.get(
+            &request.app_id,
+            &["notifications", "v2"],
+            &queries,
+            request.timeout,
+        )
+        .await
+    }
+
+    /// Request the config services one by one from the one in use, until one of them is
+    /// reachable.
+    async fn get<T: DeserializeOwned>(
+        &self, app_id: &str, path_segments: &[&str], queries: &[(String, String)],
+        timeout: Duration,
+    ) -> ApolloClientResult<T> {
+        let (urls, current) = {
+            let services = self.services.lock().unwrap();
+            (services.urls.clone(), services.current)
+        };
+        if urls.is_empty() {
+            return Err(ApolloResponseError {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                body: "no config service discovered".to_string(),
+            }
+            .into());
+        }
+
+        let mut result = None;
+        for i in 0..urls.len() {
+            let base_url = &urls[(current + i) % urls.len()];
+            match self
+                .get_from(base_url, app_id, path_segments, queries, timeout)
+                .await
+            {
+                Err(e) if is_unavailable(&e) => {
+                    log::warn!("Request config service {} failed: {}", base_url, e);
+                    self.services.lock().unwrap().set_error(base_url, &e);
+                    result = Some(Err(e));
+                }
+                r => return r,
+            }
+        }
+        result.unwrap()
+    }
+
+    async fn get_from<T: DeserializeOwned>(
+        &self, base_url: &Url, app_id: &str, path_segments: &[&str], queries: &[(String, String)],
+        timeout: Duration,
+    ) -> ApolloClientResult<T> {
+        let mut url = base_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| ApolloClientError::UrlCannotBeABase)?
+            .pop_if_empty()
+            .extend(path_segments);
+        if !queries.is_empty() {
+            url.query_pairs_mut().extend_pairs(queries);
+        }
+
+        let mut request = self.client.get(url.clone()).timeout(timeout);
+        if let Some(secret) = self.secrets.get(app_id) {
+            let timestamp = chrono::Utc::now().timestamp_millis().to_string();
+            request = request
+                .header(
+                    "Authorization",
+                    format!("Apollo {}:{}", app_id, sign(&url, &timestamp, secret)),
+                )
+                .header("Timestamp", timestamp);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(ApolloResponseError {
+                status,
+                body: response.text().await.unwrap_or_default(),
+            }
+            .into());
+        }
+        Ok(response.json().await?)
+    }
+}
+
+fn random_index(len: usize) -> usize {
+    if len == 0 {
+        return 0;
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    nanos as usize % len
+}
+
+fn check_base_url(url: &Url) -> anyhow::Result<()> {
+    if url.cannot_be_a_base() {
+        anyhow::bail!("invalid url: {}", url);
+    }
+    Ok(())
+}
+
+/// Whether the config service is unreachable or unable to serve, so the next one should be
+/// tried.
+fn is_unavailable(e: &ApolloClientError) -> bool {
+    match e {
+        ApolloClientError::Reqwest(e) => e.is_connect() || e.is_timeout(),
+        ApolloClientError::ApolloResponse(e) => e.status.is_server_error(),
+        _ => false,
+    }
+}
+
+/// Signature of the access key, that is, the base64 encoded HMAC-SHA1 of the timestamp and the
+/// path with query, joined by `\n`.
+fn sign(url: &Url, timestamp: &str, secret: &str) -> String {
+    let path_with_query = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}\n{}", timestamp, path_with_query).as_bytes());
+    base64::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_path_with_query() {
+        let url =
+            Url::parse("http://localhost:8080/configs/100004458/default/application?ip=10.0.0.1")
+                .unwrap();
+        assert_eq!(
+            sign(&url, "1576478257344", "df23df3f59884980844ff3dada30fa97"),
+            "EoKyziXvKqzHgwx+ijDJwgVTDgE="
+        );
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Supervisor running the application as a child process, with the `.properties` configurations
+//! injected as environment variables.
+
+use crate::{signal::deserialize_signal, state::State};
+use futures_util::future::select_all;
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap, os::unix::process::ExitStatusExt, process::ExitStatus, time::Duration,
+};
+use tokio::{
+    process::{Child, Command},
+    signal::unix::{signal, Signal as UnixSignal, SignalKind},
+    time::timeout,
+};
+
+/// Signals forwarded to the child process.
+const FORWARDED_SIGNALS: &[Signal] = &[
+    Signal::SIGHUP,
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTERM,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+];
+
+/// Field of config file format.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ExecConfig {
+    /// Prefix of the environment variable names.
+    pub env_prefix: String,
+
+    /// Uppercase the keys and replace the characters other than letters, digits and `_` with
+    /// `_`, such as `db.pool-size` to `DB_POOL_SIZE`.
+    pub normalize_keys: bool,
+
+    /// What to do with the child when the environment variables changed, choose Restart or
+    /// Signal.
+    pub on_change: OnChange,
+
+    /// Signal sent to the child when `on_change` is Signal.
+    #[serde(deserialize_with = "deserialize_signal")]
+    pub signal: Signal,
+
+    /// Seconds to wait for the child to exit after SIGTERM when restarting, then SIGKILL.
+    pub stop_timeout: u64,
+}
+
+impl Default for ExecConfig {
+    fn default() -> Self {
+        Self {
+            env_prefix: Default::default(),
+            normalize_keys: true,
+            on_change: OnChange::Restart,
+            signal: Signal::SIGHUP,
+            stop_timeout: 10,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OnChange {
+    Restart,
+    Signal,
+}
+
+/// Run the command after every namespace has been written, restart or signal it when the
+/// environment variables changed, return the exit code of the child.
+///
+/// `namespaces` are the app id and the namespace name pairs, the latter overrides the former if
+/// the same key exists.
+pub async fn supervise(
+    config: &ExecConfig, command: &[String], state: &State, namespaces: &[(String, String)],
+) -> anyhow::Result<i32> {
+    let mut changed = state.subscribe_changed();
+
+    let mut signals = FORWARDED_SIGNALS
+        .iter()
+        .map(|sig| Ok((*sig, signal(SignalKind::from_raw(*sig as i32))?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    tokio::select! {
+        _ = state.wait_ready() => {}
+        sig = recv_signal(&mut signals) => {
+            log::info!("Received {} before child spawned, exiting", sig);
+            return Ok(128 + sig as i32);
+        }
+    }
+
+    let mut envs = build_envs(config, state, namespaces)?;
+    let mut child = spawn(command, &envs)?;
+
+    loop {
+        tokio::select! {
+            status = child.wait() => {
+                let status = status?;
+                log::info!("Child exited: {}", status);
+                return Ok(exit_code(status));
+            }
+            sig = recv_signal(&mut signals) => {
+                send_signal(&child, sig);
+            }
+            result = changed.changed() => {
+                result?;
+                let new_envs = match build_envs(config, state, namespaces) {
+                    Ok(new_envs) => new_envs,
+                    Err(e) => {
+                        log::error!("Environment variables not changed: {:?}", e);
+                        continue;
+                    }
+                };
+                if new_envs == envs {
+                    continue;
+                }
+                envs = new_envs;
+
+                match config.on_change {
+                    OnChange::Restart => {
+                        log::info!("Environment variables changed, restarting child");
+                        stop(&mut child, Duration::from_secs(config.stop_timeout)).await?;
+                        child = spawn(command, &envs)?;
+                    }
+                    OnChange::Signal => {
+                        log::info!(
+                            "Environment variables changed, sending {} to child",
+                            config.signal
+                        );
+                        send_signal(&child, config.signal);
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn recv_signal(signals: &mut [(Signal, UnixSignal)]) -> Signal {
+    let recv = signals.iter_mut().map(|(sig, stream)| {
+        Box::pin(async move {
+            stream.recv().await;
+            *sig
+        })
+    });
+    select_all(recv).await.0
+}
+
+/// Environment variables of the configurations, fail if two keys map to the same variable.
+fn build_envs(
+    config: &ExecConfig, state: &State, namespaces: &[(String, String)],
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut envs = BTreeMap::new();
+    for (app_id, namespace) in namespaces {
+        for (key, value) in state
+            .configurations(app_id, namespace)
+            .unwrap_or_default()
+        {
+            let name = if config.normalize_keys {
+                normalize_key(&key)
+            } else {
+                key.clone()
+            };
+            if envs
+                .insert(format!("{}{}", config.env_prefix, name), value)
+                .is_some()
+            {
+                anyhow::bail!(
+                    "key {} of namespace {}/{} conflicts with another key",
+                    key,
+                    app_id,
+                    namespace
+                );
+            }
+        }
+    }
+    Ok(envs)
+}
+
+/// Uppercase the key and replace the characters other than letters and digits with `_`, so it is
+/// a valid environment variable name.
+pub fn normalize_key(key: &str) -> String {
+    key.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn spawn(command: &[String], envs: &BTreeMap<String, String>) -> anyhow::Result<Child> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("command is empty"))?;
+    // Killed if the supervisor is dropped, such as when the puller exits for other reasons.
+    let child = Command::new(program)
+        .args(args)
+        .envs(envs)
+        .kill_on_drop(true)
+        .spawn()?;
+    log::info!(
+        "Spawned child {:?} with pid {}",
+        command,
+        child.id().unwrap_or_default()
+    );
+    Ok(child)
+}
+
+fn send_signal(child: &Child, sig: Signal) {
+    if let Some(pid) = child.id() {
+        if let Err(e) = kill(Pid::from_raw(pid as i32), sig) {
+            log::error!("Send {} to child failed: {}", sig, e);
+        }
+    }
+}
+
+/// Send SIGTERM to the child and wait for it, SIGKILL if timeout.
+async fn stop(child: &mut Child, stop_timeout: Duration) -> anyhow::Result<()> {
+    send_signal(child, Signal::SIGTERM);
+    match timeout(stop_timeout, child.wait()).await {
+        Ok(status) => {
+            log::info!("Child exited: {}", status?);
+        }
+        Err(_) => {
+            log::warn!("Child not exited after {:?}, killing", stop_timeout);
+            child.kill().await?;
+        }
+    }
+    Ok(())
+}
+
+fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|sig| 128 + sig))
+        .unwrap_or(1)
+}
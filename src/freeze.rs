@@ -0,0 +1,114 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Control file of the frozen namespaces, so they stay frozen after restarts.
+//!
+//! The control file keeps the response written when frozen, which is written again on startup
+//! instead of the newer releases.
+
+use crate::{output::write_file_atomically, state::State};
+use apollo_client::conf::responses::FetchResponse;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::{fs, sync::Mutex};
+
+const FROZEN_FILE_NAME: &str = ".frozen.json";
+
+/// Frozen namespace in the control file.
+#[derive(Serialize, Deserialize)]
+struct FrozenNamespace {
+    app_id: String,
+    namespace: String,
+
+    /// Response written when frozen, `None` if not written yet.
+    response: Option<FetchResponse>,
+}
+
+/// Control file of the frozen namespaces.
+pub struct FrozenFile {
+    path: PathBuf,
+
+    /// Serializes the concurrent stores, which share the temporary file.
+    lock: Mutex<()>,
+}
+
+impl FrozenFile {
+    /// `dir` is the cache directory, or the directory of generated configuration files.
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            path: dir.join(FROZEN_FILE_NAME),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Freeze the namespaces recorded in the control file, return the responses written when
+    /// frozen, keyed by app id. A corrupt control file is ignored, nothing is frozen.
+    pub async fn restore(
+        &self, state: &State,
+    ) -> anyhow::Result<HashMap<String, Vec<(String, FetchResponse)>>> {
+        let content = match fs::read(&self.path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let frozen_namespaces = match serde_json::from_slice::<Vec<FrozenNamespace>>(&content) {
+            Ok(frozen_namespaces) => frozen_namespaces,
+            Err(e) => {
+                log::error!("Invalid control file {}, ignored: {:?}", self.path.display(), e);
+                return Ok(HashMap::new());
+            }
+        };
+
+        let mut responses = HashMap::<_, Vec<_>>::new();
+        for frozen in frozen_namespaces {
+            if !state.set_frozen(&frozen.app_id, Some(&frozen.namespace), true) {
+                log::warn!(
+                    "Frozen namespace {}/{} not found, ignored",
+                    frozen.app_id,
+                    frozen.namespace
+                );
+                continue;
+            }
+            log::info!("Namespace {}/{} frozen", frozen.app_id, frozen.namespace);
+            if let Some(response) = frozen.response {
+                responses
+                    .entry(frozen.app_id)
+                    .or_default()
+                    .push((frozen.namespace, response));
+            }
+        }
+        Ok(responses)
+    }
+
+    /// Record the frozen namespaces of the state.
+    pub async fn store(&self, state: &State) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().await;
+        let frozen = state
+            .frozen()
+            .into_iter()
+            .map(|(app_id, namespace)| FrozenNamespace {
+                response: state.written_response(&app_id, &namespace),
+                app_id,
+                namespace,
+            })
+            .collect::<Vec<_>>();
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        write_file_atomically(&self.path, &serde_json::to_vec_pretty(&frozen)?).await
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! History of the written files of every namespace, for rolling back on the node.
+//!
+//! The versions of a namespace are stored as `<dir>/<app_id>/<namespace>/<timestamp>_<release
+//! key>`, with the response it was rendered from in `.<version>.json`, and the pinned version is
+//! named in the `.pinned` file beside them.
+
+use crate::output::write_file_atomically;
+use apollo_client::{conf::responses::FetchResponse, utils::canonicalize_namespace};
+use serde::Deserialize;
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+/// Marker file containing the pinned version.
+const PINNED_FILE_NAME: &str = ".pinned";
+
+/// Field of config file format.
+#[derive(Deserialize)]
+pub struct HistoryConfig {
+    /// History directory, `<dir>.history` next to the directory of generated configuration files
+    /// by default.
+    pub dir: Option<PathBuf>,
+
+    /// Versions kept for every namespace, the pinned one is never removed.
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+fn default_limit() -> usize {
+    10
+}
+
+/// Version the namespace is pinned to.
+pub struct PinnedVersion {
+    pub version: String,
+    pub content: Vec<u8>,
+
+    /// Response the version was rendered from.
+    pub response: FetchResponse,
+}
+
+/// History directory.
+pub struct History {
+    dir: PathBuf,
+    limit: usize,
+}
+
+impl History {
+    /// `dir` is the directory of generated configuration files.
+    pub fn new(config: &HistoryConfig, dir: &Path) -> Self {
+        let dir = config.dir.clone().unwrap_or_else(|| {
+            let mut name = dir.file_name().unwrap_or_default().to_os_string();
+            name.push(".history");
+            dir.with_file_name(name)
+        });
+        Self {
+            dir,
+            limit: config.limit,
+        }
+    }
+
+    fn namespace_dir(&self, app_id: &str, namespace: &str) -> PathBuf {
+        let mut path = self.dir.clone();
+        path.push(app_id);
+        path.push(canonicalize_namespace(namespace));
+        path
+    }
+
+    /// Record the content rendered from the response as a new version unless it is the same as
+    /// the latest one, then return the pinned version, which is written instead if pinned.
+    pub async fn record(
+        &self, app_id: &str, namespace: &str, response: &FetchResponse, content: &[u8],
+    ) -> anyhow::Result<Option<PinnedVersion>> {
+        if let Err(e) = self.add_version(app_id, namespace, response, content).await {
+            log::error!("Record history of namespace {} failed: {:?}", namespace, e);
+        }
+
+        let version = match self.pinned(app_id, namespace).await? {
+            Some(version) => version,
+            None => return Ok(None),
+        };
+        log::info!(
+            "Namespace {} pinned to version {}, release {} not applied",
+            namespace,
+            version,
+            response.release_key
+        );
+        Ok(Some(PinnedVersion {
+            content: self.read(app_id, namespace, &version).await?,
+            response: self.read_response(app_id, namespace, &version).await?,
+            version,
+        }))
+    }
+
+    async fn add_version(
+        &self, app_id: &str, namespace: &str, response: &FetchResponse, content: &[u8],
+    ) -> anyhow::Result<()> {
+        let versions = self.versions(app_id, namespace).await?;
+        if let Some(latest) = versions.last() {
+            if self.read(app_id, namespace, latest).await? == content {
+                return Ok(());
+            }
+        }
+
+        let dir = self.namespace_dir(app_id, namespace);
+        fs::create_dir_all(&dir).await?;
+        let version = format!(
+            "{}_{}",
+            chrono::Local::now().format("%Y%m%d%H%M%S%3f"),
+            response
+                .release_key
+                .replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_")
+        );
+        let response = serde_json::to_vec(response)?;
+        write_file_atomically(&dir.join(response_file_name(&version)), &response).await?;
+        write_file_atomically(&dir.join(&version), content).await?;
+
+        // Remove the oldest versions beyond the limit, except the pinned one.
+        let pinned = self.pinned(app_id, namespace).await?;
+        let versions = self.versions(app_id, namespace).await?;
+        let excess = versions.len().saturating_sub(self.limit);
+        for version in versions.iter().take(excess) {
+            if pinned.as_ref() != Some(version) {
+                fs::remove_file(dir.join(version)).await?;
+                remove_file_if_exists(&dir.join(response_file_name(version))).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Versions of the namespace, from the oldest to the latest.
+    pub async fn versions(&self, app_id: &str, namespace: &str) -> anyhow::Result<Vec<String>> {
+        let mut entries = match fs::read_dir(self.namespace_dir(app_id, namespace)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut versions = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with('.') {
+                versions.push(name);
+            }
+        }
+        versions.sort();
+        Ok(versions)
+    }
+
+    /// Content of the version.
+    pub async fn read(
+        &self, app_id: &str, namespace: &str, version: &str,
+    ) -> anyhow::Result<Vec<u8>> {
+        let path = self.namespace_dir(app_id, namespace).join(version);
+        match fs::read(&path).await {
+            Ok(content) => Ok(content),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                anyhow::bail!("version {} of namespace {} not found", version, namespace)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Response the version was rendered from.
+    pub async fn read_response(
+        &self, app_id: &str, namespace: &str, version: &str,
+    ) -> anyhow::Result<FetchResponse> {
+        let path = self
+            .namespace_dir(app_id, namespace)
+            .join(response_file_name(version));
+        match fs::read(&path).await {
+            Ok(content) => Ok(serde_json::from_slice(&content)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                anyhow::bail!(
+                    "response of version {} of namespace {} not found",
+                    version,
+                    namespace
+                )
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The pinned version, `None` if not pinned.
+    pub async fn pinned(&self, app_id: &str, namespace: &str) -> anyhow::Result<Option<String>> {
+        let path = self.namespace_dir(app_id, namespace).join(PINNED_FILE_NAME);
+        match fs::read_to_string(path).await {
+            Ok(version) => Ok(Some(version.trim().to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Pin the version, which is written instead of the newer releases until unpinned.
+    pub async fn pin(&self, app_id: &str, namespace: &str, version: &str) -> anyhow::Result<()> {
+        let path = self.namespace_dir(app_id, namespace).join(PINNED_FILE_NAME);
+        write_file_atomically(&path, version.as_bytes()).await
+    }
+
+    /// Unpin the namespace, return whether it was pinned.
+    pub async fn unpin(&self, app_id: &str, namespace: &str) -> anyhow::Result<bool> {
+        let path = self.namespace_dir(app_id, namespace).join(PINNED_FILE_NAME);
+        remove_file_if_exists(&path).await
+    }
+}
+
+fn response_file_name(version: &str) -> String {
+    format!(".{}.json", version)
+}
+
+/// Remove the file, return whether it existed.
+async fn remove_file_if_exists(path: &Path) -> anyhow::Result<bool> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Commands executed after the namespace files changed.
+
+use serde::Deserialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+    process::Stdio,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+    time::timeout,
+};
+
+/// Field of config file format.
+#[derive(Deserialize, Debug)]
+pub struct Hook {
+    /// Command executed by `sh -c`.
+    pub command: String,
+
+    /// Timeout of the command in seconds, the command is killed when timeout.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+/// Changed namespaces passed to the hook by environment variables, multiple values are joined by
+/// comma.
+pub struct Changes<'a> {
+    pub app_id: &'a str,
+    pub namespaces: Vec<&'a str>,
+    pub files: Vec<&'a Path>,
+    pub changed_keys: Vec<&'a str>,
+}
+
+impl Hook {
+    /// Run the command, log the captured output and the failure.
+    pub async fn run(&self, changes: &Changes<'_>) {
+        let files = changes
+            .files
+            .iter()
+            .map(|file| file.to_string_lossy())
+            .collect::<Vec<_>>();
+
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .env("APOLLO_APP_ID", changes.app_id)
+            .env("APOLLO_NAMESPACE", changes.namespaces.join(","))
+            .env("APOLLO_FILE", files.join(","))
+            .env("APOLLO_CHANGED_KEYS", changes.changed_keys.join(","))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                log::error!("Spawn hook `{}` failed: {}", self.command, e);
+                return;
+            }
+        };
+
+        // Log the output as it comes, so it is kept even if the command is killed.
+        if let Some(stdout) = child.stdout.take() {
+            let command = self.command.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stdout).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    log::info!("Hook `{}` stdout: {}", command, line);
+                }
+            });
+        }
+        if let Some(stderr) = child.stderr.take() {
+            let command = self.command.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    log::warn!("Hook `{}` stderr: {}", command, line);
+                }
+            });
+        }
+
+        match timeout(Duration::from_secs(self.timeout), child.wait()).await {
+            Ok(Ok(status)) if status.success() => {
+                log::info!("Hook `{}` finished", self.command);
+            }
+            Ok(Ok(status)) => {
+                log::error!("Hook `{}` failed: {}", self.command, status);
+            }
+            Ok(Err(e)) => {
+                log::error!("Wait hook `{}` failed: {}", self.command, e);
+            }
+            Err(_) => {
+                log::error!(
+                    "Hook `{}` timed out after {} seconds, killed",
+                    self.command,
+                    self.timeout
+                );
+                let _ = child.kill().await;
+            }
+        }
+    }
+}
+
+/// Keys added, modified or removed, sorted.
+pub fn changed_keys(
+    previous: Option<&HashMap<String, String>>, current: &HashMap<String, String>,
+) -> Vec<String> {
+    let mut keys = BTreeSet::new();
+    for (key, value) in current {
+        if previous.and_then(|previous| previous.get(key)) != Some(value) {
+            keys.insert(key);
+        }
+    }
+    if let Some(previous) = previous {
+        keys.extend(previous.keys().filter(|key| !current.contains_key(*key)));
+    }
+    keys.into_iter().cloned().collect()
+}
@@ -0,0 +1,206 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Embedded http servers for health checking, status reporting and metrics, and for the admin
+//! endpoints.
+
+use crate::{
+    client::{Client, ConfigServiceStatus},
+    freeze::FrozenFile,
+    state::{NamespaceStatus, State},
+};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::Infallible,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+use url::form_urlencoded;
+
+/// Field of config file format.
+#[derive(Deserialize)]
+pub struct HttpConfig {
+    /// Listen address, such as `0.0.0.0:8080`.
+    pub listen: SocketAddr,
+}
+
+/// Field of config file format.
+#[derive(Deserialize)]
+pub struct AdminConfig {
+    /// Listen address, `127.0.0.1:8081` by default, only reachable on the node.
+    #[serde(default = "default_admin_listen")]
+    pub listen: SocketAddr,
+}
+
+fn default_admin_listen() -> SocketAddr {
+    (Ipv4Addr::LOCALHOST, 8081).into()
+}
+
+/// Response body of `/status`.
+#[derive(Serialize)]
+struct Status {
+    ready: bool,
+    config_services: Vec<ConfigServiceStatus>,
+    namespaces: Vec<NamespaceStatus>,
+}
+
+/// Serve `/healthz`, `/readyz`, `/status` and `/metrics` until error occurred.
+pub async fn serve(
+    config: &HttpConfig, state: Arc<State>, client: Arc<Client>,
+) -> anyhow::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        let client = client.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let state = state.clone();
+                let client = client.clone();
+                async move { Ok::<_, Infallible>(handle(request, &state, &client)) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&config.listen)?.serve(make_service);
+    log::info!("Http server listening on {}", config.listen);
+    server.await?;
+
+    Ok(())
+}
+
+/// Serve `POST /freeze` and `POST /unfreeze` until error occurred, with the query `app_id` and
+/// the optional `namespace`, stopping and resuming writing the newer releases. The frozen
+/// namespaces are recorded in the control file.
+pub async fn serve_admin(
+    config: &AdminConfig, state: Arc<State>, frozen_file: Arc<FrozenFile>,
+) -> anyhow::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        let frozen_file = frozen_file.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let state = state.clone();
+                let frozen_file = frozen_file.clone();
+                async move {
+                    Ok::<_, Infallible>(handle_admin(request, &state, &frozen_file).await)
+                }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&config.listen)?.serve(make_service);
+    log::info!("Admin http server listening on {}", config.listen);
+    server.await?;
+
+    Ok(())
+}
+
+fn handle(request: Request<Body>, state: &State, client: &Client) -> Response<Body> {
+    if request.method() != Method::GET {
+        return text_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+    }
+
+    match request.uri().path() {
+        "/healthz" => text_response(StatusCode::OK, "ok"),
+        "/readyz" => {
+            if state.is_ready() {
+                text_response(StatusCode::OK, "ok")
+            } else {
+                text_response(StatusCode::SERVICE_UNAVAILABLE, "not ready")
+            }
+        }
+        "/status" => {
+            let status = Status {
+                ready: state.is_ready(),
+                config_services: client.status(),
+                namespaces: state.status(),
+            };
+            match serde_json::to_vec(&status) {
+                Ok(body) => Response::builder()
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body.into())
+                    .unwrap(),
+                Err(e) => text_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            }
+        }
+        "/metrics" => match state.metrics().gather(&state.status()) {
+            Ok((content_type, body)) => Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .body(body.into())
+                .unwrap(),
+            Err(e) => text_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        },
+        _ => text_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+async fn handle_admin(
+    request: Request<Body>, state: &State, frozen_file: &FrozenFile,
+) -> Response<Body> {
+    if request.method() != Method::POST {
+        return text_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+    }
+
+    match request.uri().path() {
+        "/freeze" => handle_freeze(&request, state, frozen_file, true).await,
+        "/unfreeze" => handle_freeze(&request, state, frozen_file, false).await,
+        _ => text_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// Freeze or unfreeze the namespace, or all namespaces of the app if no `namespace` in query.
+async fn handle_freeze(
+    request: &Request<Body>, state: &State, frozen_file: &FrozenFile, frozen: bool,
+) -> Response<Body> {
+    let mut app_id = None;
+    let mut namespace = None;
+    let query = request.uri().query().unwrap_or_default();
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        match &*key {
+            "app_id" => app_id = Some(value.into_owned()),
+            "namespace" => namespace = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    let app_id = match app_id {
+        Some(app_id) => app_id,
+        None => return text_response(StatusCode::BAD_REQUEST, "query app_id is required"),
+    };
+    if !state.set_frozen(&app_id, namespace.as_deref(), frozen) {
+        return text_response(StatusCode::NOT_FOUND, "namespace not found");
+    }
+
+    let target = match &namespace {
+        Some(namespace) => format!("{}/{}", app_id, namespace),
+        None => app_id,
+    };
+    log::info!("{} {}", if frozen { "Froze" } else { "Unfroze" }, target);
+
+    if let Err(e) = frozen_file.store(state).await {
+        log::error!("Record frozen namespaces failed: {:?}", e);
+        return text_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+    text_response(StatusCode::OK, "ok")
+}
+
+fn text_response(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(body.into())
+        .unwrap()
+}
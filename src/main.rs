@@ -10,18 +10,64 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+mod cache;
+mod client;
+mod exec;
+mod freeze;
+mod history;
+mod hook;
+mod http;
+mod merge;
+mod metrics;
+mod output;
+mod properties;
+mod render;
+mod schema;
+mod signal;
+mod state;
+mod template;
+mod watch;
+
+use crate::{
+    cache::Cache,
+    client::Client,
+    exec::ExecConfig,
+    freeze::FrozenFile,
+    history::{History, HistoryConfig},
+    hook::{Changes, Hook},
+    http::{AdminConfig, HttpConfig},
+    merge::MergeConfig,
+    output::{
+        write_file_atomically, write_file_if_changed_with_attributes, AppDir, FileAttributes,
+        Layout,
+    },
+    render::Format,
+    schema::Schema,
+    signal::Notify,
+    state::State,
+    template::TemplateConfig,
+    watch::{Watcher, DEFAULT_CLUSTER},
+};
+use anyhow::Context;
 use apollo_client::{conf::{
-    meta::IpValue, requests::WatchRequest, ApolloConfClient, ApolloConfClientBuilder,
+    meta::IpValue, responses::FetchResponse,
 }, utils::canonicalize_namespace};
 use cidr_utils::cidr::IpCidr;
-use clap::Parser;
-use futures_util::{future::join_all, pin_mut, stream::StreamExt};
-use ini::Ini;
+use clap::{CommandFactory, ErrorKind, Parser, Subcommand};
+use futures_util::future::{self, join_all, try_join_all};
 use log::LevelFilter;
 use log4rs::{append::console::ConsoleAppender, config::Appender};
-use serde::Deserialize;
-use std::{path::{PathBuf, Path}, sync::Arc};
-use tokio::{fs::{self, File}, runtime, io::AsyncWriteExt};
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::HashMap,
+    env, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    fs, runtime,
+    signal::unix::{signal, SignalKind},
+};
 use url::Url;
 
 /// Command line arguments.
@@ -30,6 +76,42 @@ use url::Url;
 struct Args {
     #[clap(short, long)]
     config: PathBuf,
+
+    /// Pull every namespace once and exit, can be used as an init container.
+    #[clap(long)]
+    once: bool,
+
+    /// Exit once every namespace has been written at least once.
+    #[clap(long)]
+    exit_on_ready: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the application as a child process, with the configurations of `.properties`
+    /// namespaces injected as environment variables, exit with the exit code of the child.
+    Exec {
+        /// The application and its arguments.
+        #[clap(required = true, last = true)]
+        command: Vec<String>,
+    },
+
+    /// List the versions of the namespace in the history, from the oldest to the latest.
+    History { app_id: String, namespace: String },
+
+    /// Restore the version of the namespace from the history, and pin it, so the newer releases
+    /// are not written until unpinned.
+    Rollback {
+        app_id: String,
+        namespace: String,
+        version: String,
+    },
+
+    /// Unpin the namespace, and restore the latest version in the history.
+    Unpin { app_id: String, namespace: String },
 }
 
 /// Config file format.
@@ -45,12 +127,58 @@ struct Config {
     /// Directory of generated configuration files.
     dir: PathBuf,
 
-    /// Config service url of apollo.
-    config_service_url: String,
+    /// Layout of generated configuration files of each app, choose Plain or Snapshot.
+    #[serde(default)]
+    layout: Layout,
+
+    /// Config service url of apollo, or the urls of several config services, the requests fail
+    /// over to the next one if the one in use is unreachable.
+    #[serde(default, deserialize_with = "deserialize_one_or_many")]
+    config_service_url: Vec<String>,
+
+    /// Meta server url of apollo, discovering the config services instead of
+    /// `config_service_url`.
+    meta_server_url: Option<String>,
 
     /// Host identity.
     host: Option<Host>,
 
+    /// Label of this client, matched by the grey releases of apollo.
+    label: Option<String>,
+
+    /// Readiness marker file, created once every namespace has been written at least once.
+    ready_file: Option<PathBuf>,
+
+    /// Remove the readiness marker file on shutdown.
+    #[serde(default)]
+    remove_ready_file_on_exit: bool,
+
+    /// Directory caching the last good responses, restored on startup when apollo is
+    /// unreachable.
+    cache_dir: Option<PathBuf>,
+
+    /// Embedded http server exposing `/healthz`, `/readyz`, `/status` and `/metrics`.
+    http: Option<HttpConfig>,
+
+    /// Admin http server freezing and unfreezing the namespaces, the frozen namespaces are
+    /// recorded in `.frozen.json` of `cache_dir`, or of `dir` if no `cache_dir`.
+    admin: Option<AdminConfig>,
+
+    /// Options of the `exec` subcommand.
+    #[serde(default)]
+    exec: ExecConfig,
+
+    /// Templates rendered with the pulled configurations.
+    #[serde(default)]
+    templates: Vec<TemplateConfig>,
+
+    /// Merged outputs combining the configurations of several namespaces.
+    #[serde(default)]
+    merges: Vec<MergeConfig>,
+
+    /// History of the written files, for the `rollback` subcommand.
+    history: Option<HistoryConfig>,
+
     /// Apollo apps.
     apps: Vec<App>,
 }
@@ -59,18 +187,257 @@ fn default_log_level() -> LevelFilter {
     LevelFilter::Info
 }
 
+impl Config {
+    fn history(&self) -> Option<History> {
+        self.history
+            .as_ref()
+            .map(|history| History::new(history, &self.dir))
+    }
+
+    /// Check the notifies and the formats, make the namespace output paths relative to `dir`
+    /// absolute, and compile the schemas.
+    fn prepare_apps(&mut self) -> anyhow::Result<()> {
+        for app in &mut self.apps {
+            if let Some(notify) = &app.notify {
+                notify
+                    .check()
+                    .with_context(|| format!("notify of app {}", app.app_id))?;
+            }
+            for namespace in &mut app.namespaces {
+                if namespace.format.is_some() && !render::is_properties(&namespace.name) {
+                    anyhow::bail!(
+                        "format of namespace {} is only supported by properties namespaces",
+                        namespace.name
+                    );
+                }
+                if namespace.schema.is_some() && !render::supports_schema(&namespace.name) {
+                    anyhow::bail!(
+                        "schema of namespace {} is only supported by properties, json and yaml \
+                         namespaces",
+                        namespace.name
+                    );
+                }
+                if let Some(path) = &namespace.path {
+                    namespace.path = Some(self.dir.join(path));
+                }
+                if let Some(schema) = &namespace.schema {
+                    namespace.compiled_schema = Some(Schema::load(schema)?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the namespace is pulled.
+    fn check_namespace(&self, app_id: &str, namespace: &str) -> anyhow::Result<()> {
+        self.find_namespace(app_id, namespace).map(|_| ())
+    }
+
+    fn find_namespace(&self, app_id: &str, namespace: &str) -> anyhow::Result<(&App, &Namespace)> {
+        let app = self
+            .apps
+            .iter()
+            .find(|app| app.app_id == app_id)
+            .ok_or_else(|| anyhow::anyhow!("app {} not found", app_id))?;
+        let namespace = app
+            .namespace(namespace)
+            .ok_or_else(|| anyhow::anyhow!("namespace {} not found in app {}", namespace, app_id))?;
+        Ok((app, namespace))
+    }
+}
+
 /// Field of config file format.
 #[derive(Deserialize)]
 struct App {
     /// App id of apollo config app.
     app_id: String,
 
-    /// Namespaces of apollo config app ().
-    namespaces: Vec<String>,
+    /// Cluster of apollo config app, or the fallback chain such as `[idc-sh, default]`, the
+    /// namespace is pulled from the first cluster having it.
+    #[serde(default, deserialize_with = "deserialize_one_or_many")]
+    cluster: Vec<String>,
+
+    /// Namespaces of apollo config app, each one is the name or the [Namespace] detail.
+    #[serde(deserialize_with = "deserialize_namespaces")]
+    namespaces: Vec<Namespace>,
+
+    /// Label of this client for the app, overrides the global one.
+    label: Option<String>,
+
+    /// Access key secret of apollo config app, the requests are signed if set.
+    secret: Option<String>,
+
+    /// Environment variable containing the access key secret.
+    secret_env: Option<String>,
+
+    /// File containing the access key secret.
+    secret_file: Option<PathBuf>,
+
+    /// Hook executed once after the namespaces changed in the same watch response.
+    on_change: Option<Hook>,
+
+    /// Signal sent to the process once after the namespaces changed in the same watch response.
+    notify: Option<Notify>,
+}
+
+impl App {
+    /// Access key secret from `secret`, `secret_env` or `secret_file`.
+    async fn secret(&self) -> anyhow::Result<Option<String>> {
+        if let Some(secret) = &self.secret {
+            return Ok(Some(secret.clone()));
+        }
+        if let Some(secret_env) = &self.secret_env {
+            let secret = env::var(secret_env).with_context(|| {
+                format!("read secret of app {} from env {}", self.app_id, secret_env)
+            })?;
+            return Ok(Some(secret));
+        }
+        if let Some(secret_file) = &self.secret_file {
+            let secret = fs::read_to_string(secret_file).await.with_context(|| {
+                format!(
+                    "read secret of app {} from file {}",
+                    self.app_id,
+                    secret_file.display()
+                )
+            })?;
+            return Ok(Some(secret.trim().to_string()));
+        }
+        Ok(None)
+    }
+
+    fn namespace_names(&self) -> Vec<String> {
+        self.namespaces
+            .iter()
+            .map(|namespace| namespace.name.clone())
+            .collect()
+    }
+
+    /// Label of the app, or the global one.
+    fn label(&self, config: &Config) -> Option<String> {
+        self.label.clone().or_else(|| config.label.clone())
+    }
+
+    /// Namespace names and their cluster fallback chains.
+    fn watched_namespaces(&self) -> Vec<(String, Vec<String>)> {
+        self.namespaces
+            .iter()
+            .map(|namespace| (namespace.name.clone(), self.clusters(namespace)))
+            .collect()
+    }
+
+    /// Cluster fallback chain of the namespace, the one of namespace overrides the one of app.
+    fn clusters(&self, namespace: &Namespace) -> Vec<String> {
+        if !namespace.cluster.is_empty() {
+            namespace.cluster.clone()
+        } else if !self.cluster.is_empty() {
+            self.cluster.clone()
+        } else {
+            vec![DEFAULT_CLUSTER.to_string()]
+        }
+    }
+
+    /// File names and attributes of the namespaces written into the app directory.
+    fn app_dir_files(&self) -> Vec<(String, FileAttributes)> {
+        self.namespaces
+            .iter()
+            .filter(|namespace| namespace.path.is_none())
+            .map(|namespace| {
+                (
+                    render::filename_of(&namespace.name, namespace.format),
+                    namespace.attributes.clone(),
+                )
+            })
+            .collect()
+    }
+
+    /// Find the namespace, the namespace name returned by apollo may be canonicalized.
+    fn namespace(&self, name: &str) -> Option<&Namespace> {
+        let name = canonicalize_namespace(name);
+        self.namespaces
+            .iter()
+            .find(|namespace| canonicalize_namespace(&namespace.name) == name)
+    }
+}
+
+/// Field of config file format.
+#[derive(Deserialize, Default)]
+struct Namespace {
+    /// Namespace name of apollo config app.
+    name: String,
+
+    /// Cluster or the fallback chain of the namespace, overrides the one of the app.
+    #[serde(default, deserialize_with = "deserialize_one_or_many")]
+    cluster: Vec<String>,
+
+    /// Output format of the properties namespace, choose Properties, Json, Yaml, Toml or
+    /// Dotenv, the file extension follows the format.
+    format: Option<Format>,
+
+    /// Un-flatten the dotted keys into nested objects for Json, Yaml and Toml formats.
+    #[serde(default)]
+    unflatten: bool,
+
+    /// Output file of the namespace instead of the one in the app directory, absolute or relative
+    /// to `dir`.
+    path: Option<PathBuf>,
+
+    /// Mode, owner and group of the output file.
+    #[serde(flatten)]
+    attributes: FileAttributes,
+
+    /// JSON Schema file validating the configurations of properties namespace, or the content
+    /// of json and yaml namespace, the release is refused if invalid.
+    schema: Option<PathBuf>,
+
+    /// Compiled `schema`.
+    #[serde(skip)]
+    compiled_schema: Option<Schema>,
+
+    /// Hook executed after the namespace changed.
+    on_change: Option<Hook>,
+}
+
+fn deserialize_namespaces<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Namespace>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NameOrNamespace {
+        Name(String),
+        Namespace(Box<Namespace>),
+    }
+
+    Ok(Vec::<NameOrNamespace>::deserialize(deserializer)?
+        .into_iter()
+        .map(|namespace| match namespace {
+            NameOrNamespace::Name(name) => Namespace {
+                name,
+                ..Default::default()
+            },
+            NameOrNamespace::Namespace(namespace) => *namespace,
+        })
+        .collect())
+}
+
+fn deserialize_one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
 }
 
 #[derive(Deserialize)]
 #[serde(tag = "type")]
+#[allow(clippy::enum_variant_names)]
 enum Host {
     HostName,
     HostCidr { cidr: String },
@@ -79,8 +446,18 @@ enum Host {
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    // The child is supervised until it exits.
+    if matches!(args.command, Some(Command::Exec { .. })) && (args.once || args.exit_on_ready) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--once and --exit-on-ready cannot be used with exec",
+            )
+            .exit();
+    }
     let config_file = std::fs::File::open(&args.config)?;
-    let config: Config = serde_yaml::from_reader(config_file)?;
+    let mut config: Config = serde_yaml::from_reader(config_file)?;
+    config.prepare_apps()?;
     init_log(&config)?;
 
     let mut rt_builder = runtime::Builder::new_multi_thread();
@@ -90,7 +467,12 @@ fn main() -> anyhow::Result<()> {
     }
     let rt = rt_builder.build()?;
 
-    rt.block_on(run(config))?;
+    let exit_code = rt.block_on(run(config, args))?;
+    drop(rt);
+
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
 
     Ok(())
 }
@@ -104,86 +486,837 @@ fn init_log(config: &Config) -> anyhow::Result<()> {
             .build(
                 log4rs::config::Root::builder()
                     .appender("stdout")
-                    .build(config.log_level.clone()),
+                    .build(config.log_level),
             )?,
     )?;
 
     Ok(())
 }
 
-async fn run(config: Config) -> anyhow::Result<()> {
+/// Return the exit code of the child in `exec` subcommand, otherwise zero.
+async fn run(config: Config, args: Args) -> anyhow::Result<i32> {
+    match &args.command {
+        Some(Command::History { app_id, namespace }) => {
+            return list_history(&config, app_id, namespace).await.map(|_| 0);
+        }
+        Some(Command::Rollback {
+            app_id,
+            namespace,
+            version,
+        }) => return rollback(&config, app_id, namespace, version).await.map(|_| 0),
+        Some(Command::Unpin { app_id, namespace }) => {
+            return unpin(&config, app_id, namespace).await.map(|_| 0);
+        }
+        _ => {}
+    }
+
+    // The templates and the merged outputs are only fed by the pulled namespaces.
+    for template in &config.templates {
+        for namespace in &template.namespaces {
+            config
+                .check_namespace(&template.app_id, namespace)
+                .with_context(|| format!("template {}", template.template.display()))?;
+        }
+    }
+    for merge in &config.merges {
+        for source in &merge.sources {
+            config
+                .check_namespace(&source.app_id, &source.namespace)
+                .and_then(|_| {
+                    if render::is_properties(&source.namespace) {
+                        Ok(())
+                    } else {
+                        Err(anyhow::anyhow!(
+                            "namespace {} of app {} is not a properties namespace",
+                            source.namespace,
+                            source.app_id
+                        ))
+                    }
+                })
+                .with_context(|| format!("merged output {}", merge.output.display()))?;
+        }
+    }
+
     fs::create_dir_all(&config.dir).await?;
 
+    // The marker may be left by the previous run.
+    if let Some(ready_file) = &config.ready_file {
+        remove_ready_file(ready_file).await?;
+    }
+
+    let state = Arc::new(State::new(config.apps.iter().flat_map(|app| {
+        app.namespaces
+            .iter()
+            .map(move |namespace| (app.app_id.as_str(), namespace.name.as_str()))
+    })));
+
     // Create configuration client.
-    let client =
-        ApolloConfClientBuilder::new_via_config_service(Url::parse(&config.config_service_url)?)?
-            .build()?;
+    let mut secrets = HashMap::new();
+    for app in &config.apps {
+        if let Some(secret) = app.secret().await? {
+            secrets.insert(app.app_id.clone(), secret);
+        }
+    }
+    let client = match (&*config.config_service_url, &config.meta_server_url) {
+        ([_, ..], None) => {
+            let urls = config
+                .config_service_url
+                .iter()
+                .map(|url| Url::parse(url))
+                .collect::<Result<_, _>>()?;
+            Client::new(urls, secrets)?
+        }
+        ([], Some(url)) => Client::with_meta_server(Url::parse(url)?, secrets)?,
+        _ => anyhow::bail!("either config_service_url or meta_server_url should be set"),
+    };
 
     let client = Arc::new(client);
 
+    // Discover the config services before pulling, retried later if failed.
+    if let Err(e) = client.discover().await {
+        if args.once {
+            return Err(e);
+        }
+        log::error!("Discover config services failed: {:?}", e);
+    }
+
     let ip_value = config.host.as_ref().map(host_to_ip_value).transpose()?;
 
+    if args.once {
+        run_once(&config, &client, &state, ip_value).await?;
+        if let Some(ready_file) = &config.ready_file {
+            create_ready_file(ready_file).await?;
+        }
+        return Ok(0);
+    }
+
+    // The namespaces frozen before the restart stay frozen.
+    let frozen_file = config.admin.as_ref().map(|_| {
+        let dir = config.cache_dir.as_deref().unwrap_or(&config.dir);
+        Arc::new(FrozenFile::new(dir))
+    });
+    let mut frozen_responses = match &frozen_file {
+        Some(frozen_file) => frozen_file.restore(&state).await?,
+        None => HashMap::new(),
+    };
+
     let futs = config.apps.iter().map(|app| {
         let client = client.clone();
+        let state = state.clone();
+        let config = &config;
         let ip_value = ip_value.clone();
-        let base_dir = config.dir.clone();
+        let frozen_responses = frozen_responses.remove(&app.app_id).unwrap_or_default();
 
+        // Any app failing, such as its directory not writable, fails the puller.
         Box::pin(async move {
-            run_app(&client, ip_value, app, &base_dir).await;
+            run_app(&client, &state, config, ip_value, app, frozen_responses)
+                .await
+                .with_context(|| format!("app {}", app.app_id))
         })
     });
 
-    join_all(futs).await;
+    let on_ready = async {
+        state.wait_ready().await;
+        log::info!("All namespaces have been written");
+        if let Some(ready_file) = &config.ready_file {
+            create_ready_file(ready_file).await?;
+        }
+        if !args.exit_on_ready {
+            future::pending::<()>().await;
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let serve_http = async {
+        match &config.http {
+            Some(http_config) => http::serve(http_config, state.clone(), client.clone()).await,
+            None => future::pending().await,
+        }
+    };
+
+    let serve_admin = async {
+        match (&config.admin, &frozen_file) {
+            (Some(admin_config), Some(frozen_file)) => {
+                http::serve_admin(admin_config, state.clone(), frozen_file.clone()).await
+            }
+            _ => future::pending().await,
+        }
+    };
+
+    // The supervisor handles the signals itself, forwarding them to the child.
+    let supervise_or_shutdown = async {
+        match &args.command {
+            Some(Command::Exec { command }) => {
+                let namespaces = config
+                    .apps
+                    .iter()
+                    .flat_map(|app| {
+                        app.namespace_names()
+                            .into_iter()
+                            .filter(|name| canonicalize_namespace(name).ends_with(".properties"))
+                            .map(move |name| (app.app_id.clone(), name))
+                    })
+                    .collect::<Vec<_>>();
+                exec::supervise(&config.exec, command, &state, &namespaces).await
+            }
+            _ => shutdown_signal().await.map(|_| 0),
+        }
+    };
+
+    let result = tokio::select! {
+        result = try_join_all(futs) => result.map(|_| 0),
+        result = on_ready => result.map(|_| 0),
+        result = serve_http => result.map(|_| 0),
+        result = serve_admin => result.map(|_| 0),
+        result = supervise_or_shutdown => result,
+        _ = client.keep_discovering() => Ok(0),
+    };
+
+    if config.remove_ready_file_on_exit {
+        if let Some(ready_file) = &config.ready_file {
+            remove_ready_file(ready_file).await?;
+        }
+    }
+
+    result
+}
 
+async fn shutdown_signal() -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {},
+    }
+    log::info!("Shutting down");
     Ok(())
 }
 
-async fn run_app(client: &ApolloConfClient, ip_value: Option<IpValue>, app: &App, base_dir: &Path) {
-    let stream = client.watch(WatchRequest {
-        app_id: app.app_id.clone(),
-        namespace_names: app.namespaces.clone(),
-        ip: ip_value.clone(),
-        ..Default::default()
-    });
+async fn create_ready_file(ready_file: &Path) -> anyhow::Result<()> {
+    if let Some(dir) = ready_file.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let content = chrono::Local::now().to_rfc3339();
+    write_file_atomically(ready_file, content.as_bytes()).await
+}
 
-    pin_mut!(stream);
+async fn remove_ready_file(ready_file: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(ready_file).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
 
-    while let Some(responses) = stream.next().await {
-        let f = async {
-            let responses = responses?;
+/// Print the versions of the namespace in the history, the pinned one is marked.
+async fn list_history(config: &Config, app_id: &str, namespace: &str) -> anyhow::Result<()> {
+    let history = config.history().context("history is not configured")?;
+    let (_, namespace) = config.find_namespace(app_id, namespace)?;
 
-            for (_, response) in responses {
-                let response = response?;
+    let pinned = history.pinned(app_id, &namespace.name).await?;
+    for version in history.versions(app_id, &namespace.name).await? {
+        if pinned.as_ref() == Some(&version) {
+            println!("{} (pinned)", version);
+        } else {
+            println!("{}", version);
+        }
+    }
+    Ok(())
+}
+
+/// Restore the version of the namespace and pin it, the running puller keeps writing the pinned
+/// version until unpinned.
+async fn rollback(
+    config: &Config, app_id: &str, namespace: &str, version: &str,
+) -> anyhow::Result<()> {
+    let history = config.history().context("history is not configured")?;
+    let (app, namespace) = config.find_namespace(app_id, namespace)?;
 
-                let mut path = base_dir.to_path_buf();
-                path.push(response.app_id);
-                fs::create_dir_all(&path).await?;
+    let content = history.read(app_id, &namespace.name, version).await?;
+    let response = history.read_response(app_id, &namespace.name, version).await?;
+    let previous = applied_response(&history, app_id, &namespace.name).await?;
+    history.pin(app_id, &namespace.name, version).await?;
+    restore_version(config, app, namespace, content, response, previous).await?;
+    log::info!(
+        "Namespace {} rolled back to version {} and pinned",
+        namespace.name,
+        version
+    );
+    Ok(())
+}
 
-                let filename = canonicalize_namespace(&response.namespace_name);
-                let content = if filename.ends_with(".properties") {
-                    let mut content = Vec::new();
-                    let mut conf = Ini::new();
-                    for (key, value) in response.configurations {
-                        conf.with_section(None::<&str>).set(key, value);
+/// Unpin the namespace and restore the latest version, which is recorded by the running puller
+/// even if pinned.
+async fn unpin(config: &Config, app_id: &str, namespace: &str) -> anyhow::Result<()> {
+    let history = config.history().context("history is not configured")?;
+    let (app, namespace) = config.find_namespace(app_id, namespace)?;
+
+    let previous = applied_response(&history, app_id, &namespace.name).await?;
+    if !history.unpin(app_id, &namespace.name).await? {
+        log::info!("Namespace {} not pinned", namespace.name);
+        return Ok(());
+    }
+    if let Some(version) = history.versions(app_id, &namespace.name).await?.pop() {
+        let content = history.read(app_id, &namespace.name, &version).await?;
+        let response = history.read_response(app_id, &namespace.name, &version).await?;
+        restore_version(config, app, namespace, content, response, previous).await?;
+        log::info!(
+            "Namespace {} unpinned and restored to version {}",
+            namespace.name,
+            version
+        );
+    }
+    Ok(())
+}
+
+/// Response of the version in effect, which is the pinned version, or the latest one if not
+/// pinned.
+async fn applied_response(
+    history: &History, app_id: &str, namespace: &str,
+) -> anyhow::Result<Option<FetchResponse>> {
+    let version = match history.pinned(app_id, namespace).await? {
+        Some(version) => Some(version),
+        None => history.versions(app_id, namespace).await?.pop(),
+    };
+    match version {
+        Some(version) => Ok(Some(history.read_response(app_id, namespace, &version).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Write the version rendered from the response to the file of the namespace, a new snapshot is
+/// created in the snapshot layout. Run the hooks and send the signal of app if changed from the
+/// `previous` version.
+async fn restore_version(
+    config: &Config, app: &App, namespace: &Namespace, content: Vec<u8>,
+    response: FetchResponse, previous: Option<FetchResponse>,
+) -> anyhow::Result<()> {
+    let (path, changed) = match &namespace.path {
+        Some(path) => {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            let changed =
+                write_file_if_changed_with_attributes(path, &content, &namespace.attributes)
+                    .await?;
+            (path.clone(), changed)
+        }
+        None => {
+            let app_dir = config.dir.join(&app.app_id);
+            let mut app_dir = AppDir::new(app_dir, config.layout, app.app_dir_files()).await?;
+            let filename = render::filename_of(&namespace.name, namespace.format);
+            let files = vec![(filename.clone(), content, namespace.attributes.clone())];
+            let changed = app_dir.write(files).await?.contains(&filename);
+            (app_dir.file_path(&filename), changed)
+        }
+    };
+
+    if changed {
+        let pulled = Pulled {
+            namespace: namespace.name.clone(),
+            path,
+            configurations: response.configurations.clone(),
+            response,
+            previous: previous.map(|previous| previous.configurations),
+            changed,
+        };
+        notify_changes(app, &[pulled]).await;
+    }
+    Ok(())
+}
+
+async fn run_app(
+    client: &Client, state: &State, config: &Config, ip_value: Option<IpValue>,
+    app: &App, frozen_responses: Vec<(String, FetchResponse)>,
+) -> anyhow::Result<()> {
+    let mut app_dir =
+        AppDir::new(config.dir.join(&app.app_id), config.layout, app.app_dir_files()).await?;
+    let history = config.history();
+
+    let metrics = state.metrics();
+    let mut watcher = Watcher::new(
+        client,
+        app.app_id.clone(),
+        app.watched_namespaces(),
+        ip_value,
+        app.label(config),
+        metrics.long_poll_latency.with_label_values(&[&app.app_id]),
+    );
+
+    let cache = match &config.cache_dir {
+        Some(cache_dir) => Some(Cache::new(cache_dir.join(&app.app_id)).await?),
+        None => None,
+    };
+    if let Some(cache) = &cache {
+        restore_cache(cache, &mut watcher, &mut app_dir, history.as_ref(), state, app).await;
+        let namespaces = app.namespace_names();
+        let namespaces = namespaces.iter().map(String::as_str).collect::<Vec<_>>();
+        render_outputs(config, state, &app.app_id, &namespaces).await;
+    }
+
+    // The responses written when frozen, instead of the newer releases held before the restart.
+    if !frozen_responses.is_empty() {
+        let responses = frozen_responses
+            .into_iter()
+            .map(|(namespace, response)| (namespace, Ok(response)));
+        let (pulled, errors) =
+            write_responses(&mut app_dir, history.as_ref(), state, app, responses, false).await;
+        for (namespace, e) in errors {
+            log::error!("Restore frozen namespace {} failed: {:?}", namespace, e);
+        }
+        let namespaces = pulled
+            .iter()
+            .map(|pulled| pulled.namespace.as_str())
+            .collect::<Vec<_>>();
+        render_outputs(config, state, &app.app_id, &namespaces).await;
+    }
+
+    let mut unfrozen = state.subscribe_unfrozen();
+    // The latest releases of the frozen namespaces, written once unfrozen.
+    let mut held = HashMap::<String, FetchResponse>::new();
+    loop {
+        let responses = tokio::select! {
+            responses = watcher.next() => {
+                if let Ok(responses) = &responses {
+                    count_watch_responses(state, &app.app_id, responses);
+                }
+                responses
+            }
+            _ = unfrozen.changed() => {
+                let namespaces = held
+                    .keys()
+                    .filter(|namespace| !state.is_frozen(&app.app_id, namespace))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                Ok(namespaces
+                    .into_iter()
+                    .filter_map(|namespace| {
+                        let response = held.remove(&namespace)?;
+                        log::info!("Namespace {} unfrozen", namespace);
+                        Some((namespace, Ok(response)))
+                    })
+                    .collect())
+            }
+        };
+
+        let responses = match responses {
+            Ok(responses) => responses,
+            Err(e) => {
+                let e = e.into();
+                log::error!("{:?}", e);
+                metrics
+                    .request_failures
+                    .with_label_values(&[&app.app_id, ""])
+                    .inc();
+                state.set_error(&app.app_id, None, &e);
+                continue;
+            }
+        };
+
+        let responses = responses
+            .into_iter()
+            .map(|(namespace, response)| (namespace, response.map_err(Into::into)))
+            .filter_map(|(namespace, response)| match response {
+                Ok(response) if state.is_frozen(&app.app_id, &namespace) => {
+                    if state.configurations(&app.app_id, &namespace).as_ref()
+                        != Some(&response.configurations)
+                    {
+                        log::info!(
+                            "Namespace {} frozen, release {} held",
+                            namespace,
+                            response.release_key
+                        );
+                        state.set_held(&app.app_id, &namespace, &response.release_key);
                     }
-                    conf.write_to(&mut content)?;
-                    content
-                } else {
-                    let content = response.configurations.get("content").map(|s| s.as_str()).unwrap_or_default();
-                    content.as_bytes().to_vec()
+                    held.insert(namespace, response);
+                    None
+                }
+                response => Some((namespace, response)),
+            })
+            .collect::<Vec<_>>();
+        let (pulled, errors) =
+            write_responses(&mut app_dir, history.as_ref(), state, app, responses, false).await;
+        for (namespace, e) in errors {
+            log::error!("Pull namespace {} failed: {:?}", namespace, e);
+            state.set_error(&app.app_id, Some(&namespace), &e);
+        }
+
+        if let Some(cache) = &cache {
+            for pulled in &pulled {
+                let notification_ids = watcher.notification_ids(&pulled.namespace);
+                if let Err(e) = cache.store(&notification_ids, &pulled.response).await {
+                    log::error!("Cache namespace {} failed: {:?}", pulled.namespace, e);
+                }
+            }
+        }
+
+        let changed = pulled
+            .into_iter()
+            .filter(|pulled| pulled.changed)
+            .collect::<Vec<_>>();
+        if !changed.is_empty() {
+            state.notify_changed();
+        }
+
+        let namespaces = changed
+            .iter()
+            .map(|pulled| pulled.namespace.as_str())
+            .collect::<Vec<_>>();
+        render_outputs(config, state, &app.app_id, &namespaces).await;
+
+        notify_changes(app, &changed).await;
+    }
+}
+
+/// Render the templates and the merged outputs fed by the changed namespaces.
+async fn render_outputs(config: &Config, state: &State, app_id: &str, namespaces: &[&str]) {
+    template::render_changed(&config.templates, state, app_id, namespaces).await;
+    merge::render_changed(&config.merges, state, app_id, namespaces).await;
+}
+
+/// Write the last good responses in the local cache, and restore the watcher with them, the
+/// namespaces are marked stale until pulled from apollo.
+async fn restore_cache(
+    cache: &Cache, watcher: &mut Watcher<'_>, app_dir: &mut AppDir, history: Option<&History>,
+    state: &State, app: &App,
+) {
+    let mut responses = Vec::new();
+    for namespace in app.namespace_names() {
+        match cache.load(&namespace).await {
+            Ok(Some(cached)) => {
+                watcher.restore(cached.notification_ids, &cached.response);
+                responses.push((namespace, Ok(cached.response)));
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("Load cache of namespace {} failed: {:?}", namespace, e),
+        }
+    }
+
+    let (pulled, errors) = write_responses(app_dir, history, state, app, responses, true).await;
+    for (namespace, e) in errors {
+        log::error!("Restore namespace {} from cache failed: {:?}", namespace, e);
+    }
+    for pulled in pulled {
+        log::info!("Namespace {} restored from cache", pulled.namespace);
+    }
+}
+
+/// Run the hooks of the written namespaces and send the signal, the hook and the signal of app
+/// run once for all of them.
+async fn notify_changes(app: &App, written: &[Pulled]) {
+    if written.is_empty() {
+        return;
+    }
+
+    let mut all_changed_keys = Vec::new();
+    for written in written {
+        let changed_keys =
+            hook::changed_keys(written.previous.as_ref(), &written.configurations);
+
+        if let Some(hook) = app
+            .namespace(&written.namespace)
+            .and_then(|namespace| namespace.on_change.as_ref())
+        {
+            hook.run(&Changes {
+                app_id: &app.app_id,
+                namespaces: vec![&written.namespace],
+                files: vec![&written.path],
+                changed_keys: changed_keys.iter().map(String::as_str).collect(),
+            })
+            .await;
+        }
+
+        all_changed_keys.extend(changed_keys);
+    }
+
+    if let Some(hook) = &app.on_change {
+        all_changed_keys.sort();
+        all_changed_keys.dedup();
+        hook.run(&Changes {
+            app_id: &app.app_id,
+            namespaces: written
+                .iter()
+                .map(|written| written.namespace.as_str())
+                .collect(),
+            files: written.iter().map(|written| written.path.as_path()).collect(),
+            changed_keys: all_changed_keys.iter().map(String::as_str).collect(),
+        })
+        .await;
+    }
+
+    if let Some(notify) = &app.notify {
+        notify.send().await;
+    }
+}
+
+/// Pull every namespace of every app exactly once, fail with a summary if any namespace failed.
+async fn run_once(
+    config: &Config, client: &Client, state: &State, ip_value: Option<IpValue>,
+) -> anyhow::Result<()> {
+    let history = config.history();
+    let futs = config.apps.iter().map(|app| {
+        let ip_value = ip_value.clone();
+        let history = history.as_ref();
+
+        async move {
+            let app_dir = config.dir.join(&app.app_id);
+            let mut app_dir = match AppDir::new(app_dir, config.layout, app.app_dir_files()).await {
+                Ok(app_dir) => app_dir,
+                Err(e) => {
+                    return app
+                        .namespace_names()
+                        .into_iter()
+                        .map(|namespace| {
+                            (app.app_id.clone(), namespace, anyhow::anyhow!("{:?}", e))
+                        })
+                        .collect();
+                }
+            };
+
+            let responses = join_all(app.namespaces.iter().map(|namespace| async {
+                let response = watch::fetch(
+                    client,
+                    &app.app_id,
+                    &namespace.name,
+                    &app.clusters(namespace),
+                    ip_value.clone(),
+                    app.label(config),
+                    None,
+                )
+                .await;
+                (namespace.name.clone(), response.map_err(Into::into))
+            }))
+            .await;
+            count_watch_responses(state, &app.app_id, &responses);
+
+            write_responses(&mut app_dir, history, state, app, responses, false)
+                .await
+                .1
+                .into_iter()
+                .map(|(namespace, e)| (app.app_id.clone(), namespace, e))
+                .collect::<Vec<_>>()
+        }
+    });
+
+    let errors = join_all(futs).await.into_iter().flatten().collect::<Vec<_>>();
+    if !errors.is_empty() {
+        let total = config.apps.iter().map(|app| app.namespaces.len()).sum::<usize>();
+        for (app_id, namespace, e) in &errors {
+            log::error!("Pull namespace {}/{} failed: {:?}", app_id, namespace, e);
+            state.set_error(app_id, Some(namespace), e);
+        }
+        anyhow::bail!("{} of {} namespaces failed to pull", errors.len(), total)
+    }
+    log::info!("All namespaces pulled");
+
+    let mut failures = 0;
+    for template in &config.templates {
+        if let Err(e) = template.render(state).await {
+            log::error!(
+                "Render template {} failed: {:?}",
+                template.template.display(),
+                e
+            );
+            failures += 1;
+        }
+    }
+    for merge in &config.merges {
+        if let Err(e) = merge.render(state).await {
+            log::error!(
+                "Write merged output {} failed: {:?}",
+                merge.output.display(),
+                e
+            );
+            failures += 1;
+        }
+    }
+    if failures > 0 {
+        anyhow::bail!(
+            "{} of {} templates and merged outputs failed to render",
+            failures,
+            config.templates.len() + config.merges.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Namespace pulled and written to the file.
+struct Pulled {
+    namespace: String,
+    path: PathBuf,
+
+    /// Fetched response, the newer release if pinned.
+    response: FetchResponse,
+
+    /// Configurations written, of the pinned version if pinned.
+    configurations: HashMap<String, String>,
+
+    /// Configurations written last time.
+    previous: Option<HashMap<String, String>>,
+
+    /// Whether the file content is changed.
+    changed: bool,
+}
+
+/// Count the responses fetched from apollo, the restored or held responses are not counted.
+fn count_watch_responses<E>(
+    state: &State, app_id: &str, responses: &[(String, Result<FetchResponse, E>)],
+) {
+    let metrics = state.metrics();
+    for (namespace, response) in responses {
+        if response.is_ok() {
+            metrics
+                .watch_responses
+                .with_label_values(&[app_id, namespace])
+                .inc();
+        }
+    }
+}
+
+/// Render and write the fetched namespaces, return the pulled and the failed namespaces.
+///
+/// The namespaces with a custom `path` are written one by one, out of the app directory. The
+/// rendered files are recorded in the history, and the pinned versions are written instead. The
+/// `restored` responses, such as from the local cache, are not counted as pulled from apollo.
+async fn write_responses(
+    app_dir: &mut AppDir, history: Option<&History>, state: &State, app: &App,
+    responses: impl IntoIterator<Item = (String, anyhow::Result<FetchResponse>)>, restored: bool,
+) -> (Vec<Pulled>, Vec<(String, anyhow::Error)>) {
+    let app_id = app.app_id.as_str();
+    let metrics = state.metrics();
+    let mut errors = Vec::new();
+    let mut files = Vec::new();
+    let mut namespaces = Vec::new();
+    let mut custom_files = Vec::new();
+
+    for (namespace, response) in responses {
+        let labels = [app_id, namespace.as_str()];
+
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                metrics.request_failures.with_label_values(&labels).inc();
+                errors.push((namespace, e));
+                continue;
+            }
+        };
+
+        let namespace_config = app.namespace(&namespace);
+        let (format, unflatten, schema) = namespace_config
+            .map(|namespace| {
+                (namespace.format, namespace.unflatten, namespace.compiled_schema.as_ref())
+            })
+            .unwrap_or_default();
+        let attributes = namespace_config
+            .map(|namespace| namespace.attributes.clone())
+            .unwrap_or_default();
+        let rendered = async {
+            let (filename, content) = render::render(&response, format, unflatten, schema)?;
+            let pinned = match history {
+                Some(history) => history.record(app_id, &namespace, &response, &content).await?,
+                None => None,
+            };
+            Ok::<_, anyhow::Error>((filename, content, pinned))
+        }
+        .await;
+        match rendered {
+            Ok((filename, content, pinned)) => {
+                let content = match &pinned {
+                    Some(pinned) => pinned.content.clone(),
+                    None => content,
                 };
+                match namespace_config.and_then(|namespace| namespace.path.clone()) {
+                    Some(path) => {
+                        custom_files.push((namespace, response, pinned, path, content, attributes))
+                    }
+                    None => {
+                        namespaces.push((namespace, response, pinned, filename.clone()));
+                        files.push((filename, content, attributes));
+                    }
+                }
+            }
+            Err(e) => {
+                metrics.write_failures.with_label_values(&labels).inc();
+                errors.push((namespace, e));
+            }
+        }
+    }
 
-                path.push(filename);
-                let mut file = File::create(path).await?;
-                file.write_all(&content).await?;
+    let mut written = Vec::new();
+    match app_dir.write(files).await {
+        Ok(changed_files) => {
+            for (namespace, response, pinned, filename) in namespaces {
+                let path = app_dir.file_path(&filename);
+                // Only the changed files are notified.
+                let changed = changed_files.contains(&filename);
+                written.push((namespace, response, pinned, path, changed));
             }
-            Ok::<_, anyhow::Error>(())
+        }
+        Err(e) => {
+            for (namespace, ..) in namespaces {
+                metrics
+                    .write_failures
+                    .with_label_values(&[app_id, &namespace])
+                    .inc();
+                errors.push((namespace, anyhow::anyhow!("{:?}", e)));
+            }
+        }
+    }
+
+    for (namespace, response, pinned, path, content, attributes) in custom_files {
+        let result = async {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            write_file_if_changed_with_attributes(&path, &content, &attributes).await
+        }
+        .await;
+        match result {
+            Ok(changed) => written.push((namespace, response, pinned, path, changed)),
+            Err(e) => {
+                metrics
+                    .write_failures
+                    .with_label_values(&[app_id, &namespace])
+                    .inc();
+                errors.push((namespace, e));
+            }
+        }
+    }
+
+    // Record the written namespaces in the state, which keeps the pinned version if pinned, and
+    // the fetched release is held.
+    let mut pulled = Vec::new();
+    for (namespace, response, pinned, path, changed) in written {
+        let (current, version) = match &pinned {
+            Some(pinned) => (&pinned.response, Some(pinned.version.as_str())),
+            None => (&response, None),
+        };
+        let previous = if restored {
+            state.set_restored(app_id, &namespace, current, &path, version)
+        } else {
+            state.set_written(app_id, &namespace, current, &path, version)
         };
-        if let Err(e) = f.await {
-            log::error!("{:?}", e);
-            continue;
+        let configurations = current.configurations.clone();
+        if current.release_key != response.release_key {
+            state.set_held(app_id, &namespace, &response.release_key);
         }
+
+        if changed {
+            metrics.changes.with_label_values(&[app_id, &namespace]).inc();
+        } else {
+            log::debug!("Namespace {} not changed, skipped", namespace);
+        }
+
+        pulled.push(Pulled {
+            namespace,
+            path,
+            response,
+            configurations,
+            previous,
+            changed,
+        });
     }
+
+    (pulled, errors)
 }
 
 fn host_to_ip_value(host: &Host) -> anyhow::Result<IpValue> {
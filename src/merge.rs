@@ -0,0 +1,112 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Merged outputs combining the configurations of several namespaces, possibly across apps.
+
+use crate::{
+    output::write_file_if_changed,
+    render::{render_configurations, Format},
+    state::State,
+};
+use apollo_client::utils::canonicalize_namespace;
+use serde::Deserialize;
+use std::{collections::HashMap, path::PathBuf};
+use tokio::{fs, sync::Mutex};
+
+/// Field of config file format.
+#[derive(Deserialize)]
+pub struct MergeConfig {
+    /// Merged file.
+    pub output: PathBuf,
+
+    /// Format of the merged file, choose Properties, Json, Yaml, Toml or Dotenv.
+    #[serde(default)]
+    pub format: Format,
+
+    /// Un-flatten the dotted keys into nested objects for Json, Yaml and Toml formats.
+    #[serde(default)]
+    pub unflatten: bool,
+
+    /// Namespaces in precedence order, the latter overrides the former if the same key exists.
+    pub sources: Vec<MergeSource>,
+
+    /// The apps render concurrently.
+    #[serde(skip)]
+    lock: Mutex<()>,
+}
+
+/// Field of config file format.
+#[derive(Deserialize)]
+pub struct MergeSource {
+    pub app_id: String,
+    pub namespace: String,
+}
+
+impl MergeConfig {
+    /// Whether any of the changed namespaces of the app is a source.
+    fn is_fed_by(&self, app_id: &str, namespaces: &[&str]) -> bool {
+        namespaces.iter().any(|changed| {
+            let changed = canonicalize_namespace(changed);
+            self.sources.iter().any(|source| {
+                source.app_id == app_id && canonicalize_namespace(&source.namespace) == changed
+            })
+        })
+    }
+
+    /// Merge the last written configurations of the sources, skipped if some source not written
+    /// yet. Return whether the output file changed.
+    pub async fn render(&self, state: &State) -> anyhow::Result<bool> {
+        let _guard = self.lock.lock().await;
+
+        let mut configurations = HashMap::new();
+        for source in &self.sources {
+            match state.configurations(&source.app_id, &source.namespace) {
+                Some(source_configurations) => configurations.extend(source_configurations),
+                None => {
+                    log::debug!(
+                        "Namespace {}/{} not written yet, merged output {} skipped",
+                        source.app_id,
+                        source.namespace,
+                        self.output.display()
+                    );
+                    return Ok(false);
+                }
+            }
+        }
+
+        let content = render_configurations(&configurations, self.format, self.unflatten)?;
+        if let Some(dir) = self.output.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        write_file_if_changed(&self.output, &content).await
+    }
+}
+
+/// Render the merged outputs fed by the changed namespaces of the app, log the failures.
+pub async fn render_changed(
+    merges: &[MergeConfig], state: &State, app_id: &str, namespaces: &[&str],
+) {
+    for merge in merges {
+        if !merge.is_fed_by(app_id, namespaces) {
+            continue;
+        }
+        match merge.render(state).await {
+            Ok(true) => log::info!("Merged output {} written", merge.output.display()),
+            Ok(false) => {}
+            Err(e) => log::error!(
+                "Write merged output {} failed: {:?}",
+                merge.output.display(),
+                e
+            ),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Prometheus metrics.
+
+use crate::state::NamespaceStatus;
+use chrono::Local;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+/// Buckets of long polling latency, apollo holds the notification request for 60 seconds.
+const LONG_POLL_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 65.0, 90.0];
+
+/// Metrics of all apps, labeled by app id and namespace.
+pub struct Metrics {
+    registry: Registry,
+
+    /// Successful responses fetched from apollo, one for each namespace.
+    pub watch_responses: IntCounterVec,
+
+    /// Configuration changes written to the files.
+    pub changes: IntCounterVec,
+
+    /// Failures of rendering or writing the files.
+    pub write_failures: IntCounterVec,
+
+    /// Failures of requesting apollo, namespace is empty if the failure is not specific to a
+    /// namespace.
+    pub request_failures: IntCounterVec,
+
+    /// Seconds since the last successful update, refreshed when gathered.
+    seconds_since_last_update: GaugeVec,
+
+    /// Latency of the long polling requests answered by apollo, labeled by app id only.
+    pub long_poll_latency: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let labels = &["app_id", "namespace"];
+        let watch_responses = IntCounterVec::new(
+            Opts::new(
+                "apollo_puller_watch_responses_total",
+                "Successful responses fetched from apollo.",
+            ),
+            labels,
+        )
+        .unwrap();
+        let changes = IntCounterVec::new(
+            Opts::new(
+                "apollo_puller_changes_total",
+                "Configuration changes written to the files.",
+            ),
+            labels,
+        )
+        .unwrap();
+        let write_failures = IntCounterVec::new(
+            Opts::new(
+                "apollo_puller_write_failures_total",
+                "Failures of rendering or writing the files.",
+            ),
+            labels,
+        )
+        .unwrap();
+        let request_failures = IntCounterVec::new(
+            Opts::new(
+                "apollo_puller_request_failures_total",
+                "Failures of requesting apollo.",
+            ),
+            labels,
+        )
+        .unwrap();
+        let seconds_since_last_update = GaugeVec::new(
+            Opts::new(
+                "apollo_puller_seconds_since_last_update",
+                "Seconds since the last successful update.",
+            ),
+            labels,
+        )
+        .unwrap();
+        let long_poll_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "apollo_puller_long_poll_duration_seconds",
+                "Latency of the long polling requests.",
+            )
+            .buckets(LONG_POLL_BUCKETS.to_vec()),
+            &["app_id"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(watch_responses.clone())).unwrap();
+        registry.register(Box::new(changes.clone())).unwrap();
+        registry.register(Box::new(write_failures.clone())).unwrap();
+        registry.register(Box::new(request_failures.clone())).unwrap();
+        registry
+            .register(Box::new(seconds_since_last_update.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(long_poll_latency.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            watch_responses,
+            changes,
+            write_failures,
+            request_failures,
+            seconds_since_last_update,
+            long_poll_latency,
+        }
+    }
+
+    /// Encode all metrics in Prometheus text format, return the content type and the body.
+    pub fn gather(&self, status: &[NamespaceStatus]) -> anyhow::Result<(String, Vec<u8>)> {
+        let now = Local::now();
+        for status in status {
+            if let Some(last_success_time) = status.state.last_success_time {
+                let seconds = (now - last_success_time).num_milliseconds() as f64 / 1000.;
+                self.seconds_since_last_update
+                    .with_label_values(&[&status.app_id, &status.namespace])
+                    .set(seconds);
+            }
+        }
+
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::new();
+        encoder.encode(&self.registry.gather(), &mut buffer)?;
+        Ok((encoder.format_type().to_string(), buffer))
+    }
+}
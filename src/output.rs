@@ -0,0 +1,362 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Writing of generated configuration files.
+
+use nix::unistd::{chown, Gid, Group, Uid, User};
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::Permissions,
+    io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File},
+    io::AsyncWriteExt,
+};
+
+/// Symlink pointing to the current snapshot directory, same as kubelet does for ConfigMap
+/// volumes.
+const DATA_DIR_NAME: &str = "..data";
+
+/// Temporary symlink which is renamed over [DATA_DIR_NAME].
+const DATA_DIR_TMP_NAME: &str = "..data_tmp";
+
+/// Layout of the generated configuration files of an app.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// Files are written directly into the app directory, one by one.
+    #[default]
+    Plain,
+
+    /// Files are written into a timestamped snapshot directory, and the `..data` symlink is
+    /// swapped to point at it, so all namespaces of an app flip at once.
+    Snapshot,
+}
+
+/// Mode, owner and group of a generated file, the unset ones are left as created.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    /// File mode in octal, such as `"0640"`.
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    pub mode: Option<u32>,
+
+    /// Owner name or uid.
+    pub owner: Option<String>,
+
+    /// Group name or gid.
+    pub group: Option<String>,
+}
+
+impl FileAttributes {
+    /// Set the mode, owner and group of the file, the symlink is followed.
+    pub fn apply(&self, path: &Path) -> anyhow::Result<()> {
+        if self.owner.is_some() || self.group.is_some() {
+            let uid = self.owner.as_deref().map(resolve_uid).transpose()?;
+            let gid = self.group.as_deref().map(resolve_gid).transpose()?;
+            chown(path, uid, gid)?;
+        }
+        // After chown, which may clear the setuid and setgid bits.
+        if let Some(mode) = self.mode {
+            std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+}
+
+fn resolve_uid(owner: &str) -> anyhow::Result<Uid> {
+    if let Ok(uid) = owner.parse() {
+        return Ok(Uid::from_raw(uid));
+    }
+    User::from_name(owner)?
+        .map(|user| user.uid)
+        .ok_or_else(|| anyhow::anyhow!("user {} not found", owner))
+}
+
+fn resolve_gid(group: &str) -> anyhow::Result<Gid> {
+    if let Ok(gid) = group.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+    Group::from_name(group)?
+        .map(|group| group.gid)
+        .ok_or_else(|| anyhow::anyhow!("group {} not found", group))
+}
+
+/// The mode is an octal string, a yaml integer such as `0640` is ambiguous.
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    let mode = String::deserialize(deserializer)?;
+    match u32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(Some(mode)),
+        _ => Err(serde::de::Error::custom(format!(
+            "invalid file mode {:?}, should be octal such as \"0640\"",
+            mode
+        ))),
+    }
+}
+
+/// Directory of generated configuration files of an app.
+pub struct AppDir {
+    dir: PathBuf,
+    layout: Layout,
+
+    /// Content of all files written, the whole set is needed to build a new snapshot.
+    files: BTreeMap<String, Vec<u8>>,
+
+    /// Attributes of the files, applied whenever the files are written.
+    attributes: HashMap<String, FileAttributes>,
+}
+
+impl AppDir {
+    /// `files` are the file names and the attributes of all files of the app. For the Snapshot
+    /// layout, the ones in the current snapshot are carried into the new snapshots until written,
+    /// so the files of the namespaces failed to pull are not removed.
+    pub async fn new(
+        dir: PathBuf, layout: Layout, files: Vec<(String, FileAttributes)>,
+    ) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir).await?;
+        let mut app_dir = Self {
+            dir,
+            layout,
+            files: Default::default(),
+            attributes: Default::default(),
+        };
+
+        for (filename, attributes) in files {
+            if layout == Layout::Snapshot {
+                let path = app_dir.file_path(DATA_DIR_NAME).join(&filename);
+                match fs::read(path).await {
+                    Ok(content) => {
+                        app_dir.files.insert(filename.clone(), content);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            app_dir.attributes.insert(filename, attributes);
+        }
+
+        Ok(app_dir)
+    }
+
+    /// Path of the file which the consuming service reads.
+    pub fn file_path(&self, filename: &str) -> PathBuf {
+        let mut path = self.dir.clone();
+        path.push(filename);
+        path
+    }
+
+    /// Write the files whose content differs from the current one, the files not included keep
+    /// their content. Return the file names actually written.
+    pub async fn write(
+        &mut self, files: Vec<(String, Vec<u8>, FileAttributes)>,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut changed_files = Vec::new();
+        for (filename, content, attributes) in files {
+            if self.is_unchanged(&filename, &content).await {
+                // Such as the attributes configured differently from the previous run.
+                attributes.apply(&self.file_path(&filename))?;
+                self.attributes.insert(filename.clone(), attributes);
+                self.files.insert(filename, content);
+            } else {
+                self.attributes.insert(filename.clone(), attributes);
+                changed_files.push((filename, content));
+            }
+        }
+
+        if changed_files.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let filenames = changed_files
+            .iter()
+            .map(|(filename, _)| filename.clone())
+            .collect();
+
+        match self.layout {
+            Layout::Plain => {
+                for (filename, content) in changed_files {
+                    let attributes = &self.attributes[&filename];
+                    write_file_with_attributes(&self.file_path(&filename), &content, attributes)
+                        .await?;
+                    self.files.insert(filename, content);
+                }
+            }
+            Layout::Snapshot => {
+                let mut all_files = self.files.clone();
+                // The files may be replaced out of the puller, such as by the `rollback`
+                // subcommand.
+                for (filename, content) in &mut all_files {
+                    if let Ok(current) = fs::read(self.file_path(filename)).await {
+                        *content = current;
+                    }
+                }
+                all_files.extend(changed_files);
+                self.write_snapshot(&all_files).await?;
+                self.files = all_files;
+            }
+        }
+
+        Ok(filenames)
+    }
+
+    /// Compare with the current content on disk, such as the files written by the previous run or
+    /// restored by the `rollback` subcommand.
+    async fn is_unchanged(&self, filename: &str, content: &[u8]) -> bool {
+        fs::read(self.file_path(filename))
+            .await
+            .map(|current| current == content)
+            .unwrap_or_default()
+    }
+
+    async fn write_snapshot(&self, files: &BTreeMap<String, Vec<u8>>) -> anyhow::Result<()> {
+        let snapshot_name = format!(
+            "..{}",
+            chrono::Local::now().format("%Y_%m_%d_%H_%M_%S.%9f")
+        );
+        let mut snapshot_dir = self.dir.clone();
+        snapshot_dir.push(&snapshot_name);
+
+        fs::create_dir(&snapshot_dir).await?;
+        for (filename, content) in files {
+            let mut path = snapshot_dir.clone();
+            path.push(filename);
+            let mut file = File::create(&path).await?;
+            file.write_all(content).await?;
+            file.sync_all().await?;
+            if let Some(attributes) = self.attributes.get(filename) {
+                attributes.apply(&path)?;
+            }
+        }
+        File::open(&snapshot_dir).await?.sync_all().await?;
+
+        // Atomically swap the `..data` symlink to the new snapshot.
+        let data_dir = self.file_path(DATA_DIR_NAME);
+        let data_tmp_dir = self.file_path(DATA_DIR_TMP_NAME);
+        let _ = fs::remove_file(&data_tmp_dir).await;
+        fs::symlink(&snapshot_name, &data_tmp_dir).await?;
+        fs::rename(&data_tmp_dir, &data_dir).await?;
+
+        // Make the user visible files point into `..data`.
+        for filename in files.keys() {
+            let target = Path::new(DATA_DIR_NAME).join(filename);
+            let path = self.file_path(filename);
+            if fs::read_link(&path).await.ok().as_deref() == Some(&*target) {
+                continue;
+            }
+            let tmp_path = self.file_path(&format!(".{}.tmp", filename));
+            let _ = fs::remove_file(&tmp_path).await;
+            fs::symlink(&target, &tmp_path).await?;
+            fs::rename(&tmp_path, &path).await?;
+        }
+
+        File::open(&self.dir).await?.sync_all().await?;
+
+        self.remove_stale_entries(&snapshot_name, files).await
+    }
+
+    /// Remove the old snapshots and the symlinks of files no longer pulled.
+    async fn remove_stale_entries(
+        &self,
+        snapshot_name: &str,
+        files: &BTreeMap<String, Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+
+            let result = if name.starts_with("..") {
+                if name == DATA_DIR_NAME || name == DATA_DIR_TMP_NAME || name == snapshot_name {
+                    continue;
+                }
+                fs::remove_dir_all(entry.path()).await
+            } else {
+                match fs::read_link(entry.path()).await {
+                    Ok(target)
+                        if target.starts_with(DATA_DIR_NAME) && !files.contains_key(&*name) =>
+                    {
+                        fs::remove_file(entry.path()).await
+                    }
+                    _ => continue,
+                }
+            };
+
+            if let Err(e) = result {
+                log::warn!("Remove stale entry {:?} failed: {}", entry.path(), e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write the content atomically unless the file has the same content, return whether written.
+pub async fn write_file_if_changed(path: &Path, content: &[u8]) -> anyhow::Result<bool> {
+    write_file_if_changed_with_attributes(path, content, &Default::default()).await
+}
+
+/// Same as [write_file_if_changed], the attributes are applied even if the content is the same.
+pub async fn write_file_if_changed_with_attributes(
+    path: &Path, content: &[u8], attributes: &FileAttributes,
+) -> anyhow::Result<bool> {
+    if fs::read(path).await.ok().as_deref() == Some(content) {
+        attributes.apply(path)?;
+        return Ok(false);
+    }
+    write_file_with_attributes(path, content, attributes).await?;
+    Ok(true)
+}
+
+/// Write the content to a temporary file in the same directory, fsync it and rename it over the
+/// target, so readers always see either the old or the new full content.
+pub async fn write_file_atomically(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    write_file_with_attributes(path, content, &Default::default()).await
+}
+
+/// Same as [write_file_atomically], the attributes are applied to the temporary file before
+/// renamed, so the content is never exposed with the wrong permissions.
+pub async fn write_file_with_attributes(
+    path: &Path, content: &[u8], attributes: &FileAttributes,
+) -> anyhow::Result<()> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("invalid file path: {}", path.display()))?;
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("invalid file path: {}", path.display()))?;
+
+    let mut tmp_path = dir.to_path_buf();
+    tmp_path.push(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    let result = async {
+        let mut file = File::create(&tmp_path).await?;
+        file.write_all(content).await?;
+        file.sync_all().await?;
+        drop(file);
+        attributes.apply(&tmp_path)?;
+
+        fs::rename(&tmp_path, path).await?;
+
+        // Make the rename itself durable.
+        File::open(dir).await?.sync_all().await?;
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path).await;
+    }
+
+    result
+}
@@ -0,0 +1,112 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Serializer of the Java `.properties` format, escaping the same as `Properties.store`, so
+//! `Properties.load` reads the configurations back identically.
+
+use std::{collections::HashMap, fmt::Write};
+
+/// Serialize the configurations as `key=value` lines sorted by key, without the timestamp
+/// comment of `Properties.store`, so the same configurations always produce the same content.
+///
+/// The non-ASCII characters are escaped as `\uXXXX`, the content is pure ASCII and readable in
+/// both ISO-8859-1 and UTF-8.
+pub fn to_string(configurations: &HashMap<String, String>) -> String {
+    let mut entries = configurations.iter().collect::<Vec<_>>();
+    entries.sort();
+
+    let mut content = String::new();
+    for (key, value) in entries {
+        escape(&mut content, key, true);
+        content.push('=');
+        escape(&mut content, value, false);
+        content.push('\n');
+    }
+    content
+}
+
+/// Escape like `Properties.saveConvert`, all spaces of the key and the leading space of the
+/// value are escaped, otherwise they are trimmed or taken as the separator while loading.
+fn escape(out: &mut String, s: &str, is_key: bool) {
+    for (i, c) in s.chars().enumerate() {
+        match c {
+            ' ' if i == 0 || is_key => out.push_str("\\ "),
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\x0c' => out.push_str("\\f"),
+            '=' | ':' | '#' | '!' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' '..='~' => out.push(c),
+            _ => {
+                // Java chars are UTF-16 code units, the supplementary characters take two.
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    let _ = write!(out, "\\u{:04X}", unit);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_string_of(entries: &[(&str, &str)]) -> String {
+        to_string(
+            &entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn escape_spaces() {
+        assert_eq!(to_string_of(&[(" a b ", " c d ")]), "\\ a\\ b\\ =\\ c d \n");
+        assert_eq!(to_string_of(&[("a", "")]), "a=\n");
+    }
+
+    #[test]
+    fn escape_separators_and_comments() {
+        assert_eq!(to_string_of(&[("a=b:c", "#d!e=f:g")]), "a\\=b\\:c=\\#d\\!e\\=f\\:g\n");
+        assert_eq!(to_string_of(&[("#a", "!b")]), "\\#a=\\!b\n");
+    }
+
+    #[test]
+    fn escape_special_characters() {
+        assert_eq!(
+            to_string_of(&[("a\tb", "c\nd\te\x0cf\rg\\h")]),
+            "a\\tb=c\\nd\\te\\ff\\rg\\\\h\n"
+        );
+    }
+
+    #[test]
+    fn escape_non_printable_and_non_ascii() {
+        assert_eq!(to_string_of(&[("a", "\x01\x1f\x7f")]), "a=\\u0001\\u001F\\u007F\n");
+        assert_eq!(to_string_of(&[("é", "中文")]), "\\u00E9=\\u4E2D\\u6587\n");
+        // Surrogate pair of U+1F600.
+        assert_eq!(to_string_of(&[("a", "😀")]), "a=\\uD83D\\uDE00\n");
+    }
+
+    #[test]
+    fn sort_by_key() {
+        assert_eq!(
+            to_string_of(&[("b", "2"), ("a.b", "3"), ("a", "1"), ("B", "4")]),
+            "B=4\na=1\na.b=3\nb=2\n"
+        );
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Rendering of the fetched namespaces to the configuration files.
+
+use crate::{exec, properties, schema::Schema};
+use apollo_client::{conf::responses::FetchResponse, utils::canonicalize_namespace};
+use anyhow::Context;
+use serde::{de::IgnoredAny, Deserialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    path::Path,
+};
+
+const PROPERTIES_EXTENSION: &str = ".properties";
+
+/// Output format of the properties namespaces and the merged outputs.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Properties,
+    Json,
+    Yaml,
+    Toml,
+    Dotenv,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Properties => PROPERTIES_EXTENSION,
+            Format::Json => ".json",
+            Format::Yaml => ".yaml",
+            Format::Toml => ".toml",
+            Format::Dotenv => ".env",
+        }
+    }
+}
+
+/// Render the fetched namespace to the file name and the file content.
+///
+/// The properties namespaces are rendered in `format`, and the dotted keys are un-flattened into
+/// nested objects for the structured formats if `unflatten`. The other namespaces are written as
+/// they are, after their syntax is checked according to the extension, so a broken release never
+/// replaces the working file.
+///
+/// The configurations of properties namespaces, or the parsed content of json and yaml
+/// namespaces, are validated against the `schema` if set, every document of a multi-document
+/// yaml is validated separately.
+pub fn render(
+    response: &FetchResponse, format: Option<Format>, unflatten: bool, schema: Option<&Schema>,
+) -> anyhow::Result<(String, Vec<u8>)> {
+    let filename = canonicalize_namespace(&response.namespace_name);
+    if !is_properties(&filename) {
+        let content = response
+            .configurations
+            .get("content")
+            .map(|s| s.as_str())
+            .unwrap_or_default();
+        check_syntax(&filename, content)
+            .with_context(|| format!("invalid content of namespace {}", filename))?;
+        if let Some(schema) = schema {
+            for document in parse_documents(&filename, content)? {
+                schema.validate(&document)?;
+            }
+        }
+        return Ok((filename, content.as_bytes().to_vec()));
+    }
+
+    if let Some(schema) = schema {
+        schema.validate(&to_value(&response.configurations, unflatten)?)?;
+    }
+
+    let format = format.unwrap_or_default();
+    let content = render_configurations(&response.configurations, format, unflatten)?;
+    Ok((filename_of(&filename, Some(format)), content))
+}
+
+/// Whether the namespace is a properties namespace, the only kind supporting `format`.
+pub fn is_properties(namespace: &str) -> bool {
+    canonicalize_namespace(namespace).ends_with(PROPERTIES_EXTENSION)
+}
+
+/// Whether the namespace supports `schema`, the structured namespaces only.
+pub fn supports_schema(namespace: &str) -> bool {
+    let filename = canonicalize_namespace(namespace);
+    matches!(
+        Path::new(&filename).extension().and_then(OsStr::to_str),
+        Some("properties" | "json" | "yaml" | "yml")
+    )
+}
+
+/// File name of the namespace in the app directory, the extension of properties namespace
+/// follows the format.
+pub fn filename_of(namespace: &str, format: Option<Format>) -> String {
+    let filename = canonicalize_namespace(namespace);
+    match filename.strip_suffix(PROPERTIES_EXTENSION) {
+        Some(basename) => format!("{}{}", basename, format.unwrap_or_default().extension()),
+        None => filename,
+    }
+}
+
+/// Parse the content of json, yaml and xml namespaces, the error has the line and column.
+fn check_syntax(filename: &str, content: &str) -> anyhow::Result<()> {
+    match Path::new(filename).extension().and_then(OsStr::to_str) {
+        Some("json") => {
+            serde_json::from_str::<IgnoredAny>(content)?;
+        }
+        Some("yaml" | "yml") => {
+            for document in serde_yaml::Deserializer::from_str(content) {
+                IgnoredAny::deserialize(document)?;
+            }
+        }
+        Some("xml") => {
+            let options = roxmltree::ParsingOptions { allow_dtd: true };
+            roxmltree::Document::parse_with_options(content, options)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Parse the documents of json and yaml namespaces to json values for the schema validation, the
+/// yaml without document, like empty or comment-only content, is a null document.
+fn parse_documents(filename: &str, content: &str) -> anyhow::Result<Vec<Value>> {
+    match Path::new(filename).extension().and_then(OsStr::to_str) {
+        Some("json") => Ok(vec![serde_json::from_str(content)?]),
+        Some("yaml" | "yml") => {
+            let mut documents = serde_yaml::Deserializer::from_str(content)
+                .map(Value::deserialize)
+                .collect::<Result<Vec<_>, _>>()?;
+            if documents.is_empty() {
+                documents.push(Value::Null);
+            }
+            Ok(documents)
+        }
+        _ => anyhow::bail!("schema is only supported by properties, json and yaml namespaces"),
+    }
+}
+
+/// Render the configurations in `format`, the dotted keys are un-flattened into nested objects
+/// for the structured formats if `unflatten`.
+pub fn render_configurations(
+    configurations: &HashMap<String, String>, format: Format, unflatten: bool,
+) -> anyhow::Result<Vec<u8>> {
+    Ok(match format {
+        Format::Properties => properties::to_string(configurations).into_bytes(),
+        Format::Json => {
+            let mut content = serde_json::to_vec_pretty(&to_value(configurations, unflatten)?)?;
+            content.push(b'\n');
+            content
+        }
+        Format::Yaml => serde_yaml::to_vec(&to_value(configurations, unflatten)?)?,
+        Format::Toml => {
+            let value = toml::Value::try_from(to_value(configurations, unflatten)?)?;
+            toml::to_string(&value)?.into_bytes()
+        }
+        Format::Dotenv => render_dotenv(configurations)?,
+    })
+}
+
+/// Render `KEY='value'` lines, the keys are normalized like the environment variables of `exec`,
+/// such as `db.pool-size` to `DB_POOL_SIZE`. The values are single quoted to be taken literally,
+/// or double quoted and escaped if they contain single quotes or newlines.
+fn render_dotenv(configurations: &HashMap<String, String>) -> anyhow::Result<Vec<u8>> {
+    let mut envs = BTreeMap::new();
+    for (key, value) in configurations.iter().collect::<BTreeMap<_, _>>() {
+        if envs.insert(exec::normalize_key(key), value).is_some() {
+            anyhow::bail!("key {} conflicts with another key", key);
+        }
+    }
+
+    let mut content = String::new();
+    for (key, value) in envs {
+        if value.contains(['\'', '\n']) {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+                .replace('$', "\\$")
+                .replace('`', "\\`");
+            content.push_str(&format!("{}=\"{}\"\n", key, value));
+        } else {
+            content.push_str(&format!("{}='{}'\n", key, value));
+        }
+    }
+    Ok(content.into_bytes())
+}
+
+/// Convert the configurations to a json object, the keys such as `db.pool.size` are nested into
+/// `{"db": {"pool": {"size": ...}}}` if `unflatten`.
+fn to_value(configurations: &HashMap<String, String>, unflatten: bool) -> anyhow::Result<Value> {
+    let mut root = Map::new();
+    // Sorted, the map may preserve the insertion order.
+    for (key, value) in configurations.iter().collect::<BTreeMap<_, _>>() {
+        if !unflatten {
+            root.insert(key.clone(), Value::String(value.clone()));
+            continue;
+        }
+
+        let mut parts = key.split('.').collect::<Vec<_>>();
+        let last = parts.pop().unwrap_or_default();
+        let mut object = &mut root;
+        for part in parts {
+            object = match object
+                .entry(part)
+                .or_insert_with(|| Value::Object(Map::new()))
+            {
+                Value::Object(object) => object,
+                _ => anyhow::bail!("key {} conflicts with another key", key),
+            };
+        }
+        if object
+            .insert(last.to_string(), Value::String(value.clone()))
+            .is_some()
+        {
+            anyhow::bail!("key {} conflicts with another key", key);
+        }
+    }
+    Ok(Value::Object(root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_syntax_of_empty_yaml() {
+        for content in ["", "  \n", "# comment only\n"] {
+            check_syntax("app.yaml", content).unwrap();
+            assert_eq!(parse_documents("app.yml", content).unwrap(), vec![Value::Null]);
+        }
+        assert!(check_syntax("app.yaml", "a: [1").is_err());
+    }
+
+    #[test]
+    fn check_syntax_of_multi_document_yaml() {
+        let content = "a: 1\n---\nb: 2\n";
+        check_syntax("app.yaml", content).unwrap();
+        assert_eq!(
+            parse_documents("app.yaml", content).unwrap(),
+            vec![serde_json::json!({"a": 1}), serde_json::json!({"b": 2})]
+        );
+        assert!(check_syntax("app.yaml", "a: 1\n---\nb: [2\n").is_err());
+    }
+
+    #[test]
+    fn check_syntax_of_yaml_with_complex_keys() {
+        check_syntax("app.yaml", "? [a, b]\n: 1\n").unwrap();
+    }
+
+    fn configurations(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn unflatten_dotted_keys() {
+        let configurations = configurations(&[("a.b", "1"), ("a.c.d", "2"), ("e", "3")]);
+        assert_eq!(
+            to_value(&configurations, true).unwrap(),
+            serde_json::json!({"a": {"b": "1", "c": {"d": "2"}}, "e": "3"})
+        );
+        assert_eq!(
+            to_value(&configurations, false).unwrap(),
+            serde_json::json!({"a.b": "1", "a.c.d": "2", "e": "3"})
+        );
+    }
+
+    #[test]
+    fn unflatten_conflicting_keys() {
+        for entries in [[("a", "1"), ("a.b", "2")], [("a.b", "1"), ("a.b.c", "2")]] {
+            let configurations = configurations(&entries);
+            let e = to_value(&configurations, true).unwrap_err();
+            assert_eq!(e.to_string(), format!("key {} conflicts with another key", entries[1].0));
+            to_value(&configurations, false).unwrap();
+        }
+    }
+
+    #[test]
+    fn render_dotenv_quoted() {
+        let configurations = configurations(&[
+            ("db.pool-size", "10"),
+            ("home", "$HOME `pwd` \\"),
+            ("quote", "it's \"$HOME\""),
+            ("lines", "a\nb"),
+        ]);
+        assert_eq!(
+            String::from_utf8(render_dotenv(&configurations).unwrap()).unwrap(),
+            "DB_POOL_SIZE='10'\nHOME='$HOME `pwd` \\'\nLINES=\"a\\nb\"\n\
+             QUOTE=\"it's \\\"\\$HOME\\\"\"\n"
+        );
+    }
+
+    #[test]
+    fn render_dotenv_conflicts() {
+        let configurations = configurations(&[("db.url", "a"), ("DB_URL", "b")]);
+        let e = render_dotenv(&configurations).unwrap_err();
+        assert_eq!(e.to_string(), "key db.url conflicts with another key");
+    }
+
+    #[test]
+    fn check_syntax_of_xml_with_dtd() {
+        let content = "<?xml version=\"1.0\"?>\n<!DOCTYPE a [<!ENTITY b \"c\">]>\n<a>&b;</a>\n";
+        check_syntax("app.xml", content).unwrap();
+        assert!(check_syntax("app.xml", "<a></b>").is_err());
+    }
+}
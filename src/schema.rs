@@ -0,0 +1,64 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! [JSON Schema](https://json-schema.org/) validation of the pulled configurations.
+
+use anyhow::Context;
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// Compiled JSON Schema of a namespace.
+pub struct Schema {
+    path: PathBuf,
+    schema: JSONSchema,
+}
+
+impl Schema {
+    /// Read and compile the schema file, loaded once on startup.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read(path)
+            .with_context(|| format!("read schema {}", path.display()))?;
+        let value = serde_json::from_slice::<Value>(&content)
+            .with_context(|| format!("parse schema {}", path.display()))?;
+        let schema = JSONSchema::compile(&value)
+            .map_err(|e| anyhow::anyhow!("compile schema {} failed: {}", path.display(), e))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            schema,
+        })
+    }
+
+    /// Validate the value, the error lists every violation with its json pointer.
+    pub fn validate(&self, value: &Value) -> anyhow::Result<()> {
+        if let Err(errors) = self.schema.validate(value) {
+            let errors = errors
+                .map(|e| format!("\n  {}: {}", display_pointer(&e.instance_path.to_string()), e))
+                .collect::<String>();
+            anyhow::bail!(
+                "configurations violate schema {}:{}",
+                self.path.display(),
+                errors
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The pointer of the root is empty.
+fn display_pointer(pointer: &str) -> &str {
+    if pointer.is_empty() {
+        "/"
+    } else {
+        pointer
+    }
+}
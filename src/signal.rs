@@ -0,0 +1,123 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Signals sent to processes after the namespace files changed.
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use serde::{de, Deserialize, Deserializer};
+use std::{path::PathBuf, str::FromStr};
+use tokio::fs;
+
+/// Field of config file format, the process is identified by `pid_file` or `process_name`.
+#[derive(Deserialize)]
+pub struct Notify {
+    /// Signal name, such as `SIGHUP`.
+    #[serde(default = "default_signal", deserialize_with = "deserialize_signal")]
+    pub signal: Signal,
+
+    /// File containing the pid of the process.
+    pub pid_file: Option<PathBuf>,
+
+    /// Name of the processes, matched against `/proc/<pid>/comm`, requires shared pid namespace
+    /// in a pod.
+    pub process_name: Option<String>,
+}
+
+fn default_signal() -> Signal {
+    Signal::SIGHUP
+}
+
+pub fn deserialize_signal<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Signal, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    let name = name.to_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    Signal::from_str(&name).map_err(|_| de::Error::custom(format!("unknown signal: {}", name)))
+}
+
+impl Notify {
+    /// Check that the process is identified.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.pid_file.is_none() && self.process_name.is_none() {
+            anyhow::bail!("either pid_file or process_name should be set");
+        }
+        Ok(())
+    }
+
+    /// Send the signal to the processes, log the failure.
+    pub async fn send(&self) {
+        let pids = match self.find_pids().await {
+            Ok(pids) => pids,
+            Err(e) => {
+                log::error!("Find process to send {} failed: {:?}", self.signal, e);
+                return;
+            }
+        };
+
+        if pids.is_empty() {
+            log::warn!("No process found to send {}", self.signal);
+        }
+
+        for pid in pids {
+            match kill(pid, self.signal) {
+                Ok(()) => log::info!("Sent {} to process {}", self.signal, pid),
+                Err(e) => log::error!("Send {} to process {} failed: {}", self.signal, pid, e),
+            }
+        }
+    }
+
+    async fn find_pids(&self) -> anyhow::Result<Vec<Pid>> {
+        let mut pids = Vec::new();
+
+        if let Some(pid_file) = &self.pid_file {
+            let content = fs::read_to_string(pid_file).await?;
+            let pid = content
+                .trim()
+                .parse::<i32>()
+                .map_err(|e| anyhow::anyhow!("invalid pid in {}: {}", pid_file.display(), e))?;
+            // Zero and negative pids signal process groups, even the puller itself.
+            if pid <= 0 {
+                anyhow::bail!("invalid pid in {}: {}", pid_file.display(), pid);
+            }
+            pids.push(Pid::from_raw(pid));
+        }
+
+        if let Some(process_name) = &self.process_name {
+            // The comm of the process is truncated to 15 bytes.
+            let process_name = process_name.get(..15).unwrap_or(process_name);
+            let current_pid = std::process::id() as i32;
+            let mut entries = fs::read_dir("/proc").await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let pid = match entry.file_name().to_string_lossy().parse::<i32>() {
+                    Ok(pid) if pid != current_pid => pid,
+                    _ => continue,
+                };
+                let mut comm_path = entry.path();
+                comm_path.push("comm");
+                // The process may have exited.
+                if let Ok(comm) = fs::read_to_string(comm_path).await {
+                    if comm.trim_end() == process_name {
+                        pids.push(Pid::from_raw(pid));
+                    }
+                }
+            }
+        }
+
+        Ok(pids)
+    }
+}
@@ -0,0 +1,305 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! State of the pulled namespaces shared by all apps.
+
+use crate::metrics::Metrics;
+use apollo_client::{conf::responses::FetchResponse, utils::canonicalize_namespace};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tokio::sync::watch;
+
+/// State of a pulled namespace.
+#[derive(Default, Clone, Serialize)]
+pub struct NamespaceState {
+    /// Time of the last successful pull and write.
+    pub last_success_time: Option<DateTime<Local>>,
+
+    /// Cluster of the last written configuration, may be a fallback one.
+    pub cluster: Option<String>,
+
+    /// Release key of the last written configuration.
+    pub release_key: Option<String>,
+
+    /// The last error occurred when pulling or writing.
+    pub last_error: Option<String>,
+
+    /// Time of the last error.
+    pub last_error_time: Option<DateTime<Local>>,
+
+    /// Path of the generated configuration file.
+    pub path: Option<PathBuf>,
+
+    /// Whether the file is restored from the local cache, and not pulled from apollo yet.
+    pub stale: bool,
+
+    /// Whether the newer releases are held instead of written.
+    pub frozen: bool,
+
+    /// Release key of the newer release held while frozen or pinned.
+    pub held_release_key: Option<String>,
+
+    /// Version of the history the namespace is pinned to, written instead of the newer releases.
+    pub pinned: Option<String>,
+
+    /// The last written configurations.
+    #[serde(skip)]
+    pub configurations: Option<HashMap<String, String>>,
+}
+
+/// Status of a pulled namespace, reported by the `/status` endpoint.
+#[derive(Serialize)]
+pub struct NamespaceStatus {
+    pub app_id: String,
+    pub namespace: String,
+    #[serde(flatten)]
+    pub state: NamespaceState,
+}
+
+/// State of all namespaces listed in config, keyed by app id and namespace.
+pub struct State {
+    namespaces: Mutex<BTreeMap<(String, String), NamespaceState>>,
+    ready: watch::Sender<bool>,
+    changed: watch::Sender<()>,
+    unfrozen: watch::Sender<()>,
+    metrics: Metrics,
+}
+
+impl State {
+    pub fn new<'a>(namespaces: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let namespaces = namespaces
+            .into_iter()
+            .map(|(app_id, namespace)| {
+                (
+                    (app_id.to_string(), namespace.to_string()),
+                    Default::default(),
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+        let (ready, _) = watch::channel(namespaces.is_empty());
+        let (changed, _) = watch::channel(());
+        let (unfrozen, _) = watch::channel(());
+
+        Self {
+            namespaces: Mutex::new(namespaces),
+            ready,
+            changed,
+            unfrozen,
+            metrics: Metrics::new(),
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Mark the namespace pulled from apollo and written, the namespace name returned by apollo
+    /// may be canonicalized. The `response` is the one of the `pinned` version if pinned.
+    ///
+    /// Return the configurations written last time.
+    pub fn set_written(
+        &self, app_id: &str, namespace: &str, response: &FetchResponse, path: &Path,
+        pinned: Option<&str>,
+    ) -> Option<HashMap<String, String>> {
+        self.update_written(app_id, namespace, response, path, pinned, true)
+    }
+
+    /// Mark the namespace restored from the local cache and written, it is stale until pulled
+    /// from apollo, and neither counted as a success nor towards the readiness.
+    ///
+    /// Return the configurations written last time.
+    pub fn set_restored(
+        &self, app_id: &str, namespace: &str, response: &FetchResponse, path: &Path,
+        pinned: Option<&str>,
+    ) -> Option<HashMap<String, String>> {
+        self.update_written(app_id, namespace, response, path, pinned, false)
+    }
+
+    fn update_written(
+        &self, app_id: &str, namespace: &str, response: &FetchResponse, path: &Path,
+        pinned: Option<&str>, pulled: bool,
+    ) -> Option<HashMap<String, String>> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+
+        let now = Local::now();
+        let mut previous = None;
+        for state in find_namespaces(&mut namespaces, app_id, Some(namespace)) {
+            if pulled {
+                state.last_success_time = Some(now);
+            }
+            state.cluster = Some(response.cluster.clone());
+            state.release_key = Some(response.release_key.clone());
+            state.path = Some(path.to_path_buf());
+            state.stale = !pulled;
+            state.held_release_key = None;
+            state.pinned = pinned.map(ToOwned::to_owned);
+            previous = state
+                .configurations
+                .replace(response.configurations.clone());
+        }
+
+        if pulled
+            && !*self.ready.borrow()
+            && namespaces
+                .values()
+                .all(|state| state.last_success_time.is_some())
+        {
+            self.ready.send_replace(true);
+        }
+
+        previous
+    }
+
+    /// Notify the receivers that namespaces were written, called once for all namespaces in the
+    /// same watch response.
+    pub fn notify_changed(&self) {
+        self.changed.send_replace(());
+    }
+
+    /// The last written configurations of the namespace.
+    pub fn configurations(&self, app_id: &str, namespace: &str) -> Option<HashMap<String, String>> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        let configurations = find_namespaces(&mut namespaces, app_id, Some(namespace))
+            .find_map(|state| state.configurations.clone());
+        configurations
+    }
+
+    /// Receiver notified every time namespaces are written.
+    pub fn subscribe_changed(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    /// Record the error of the namespace, or of all namespaces of the app if `namespace` is
+    /// `None`.
+    pub fn set_error(&self, app_id: &str, namespace: Option<&str>, error: &anyhow::Error) {
+        let mut namespaces = self.namespaces.lock().unwrap();
+
+        let now = Local::now();
+        for state in find_namespaces(&mut namespaces, app_id, namespace) {
+            state.last_error = Some(format!("{:#}", error));
+            state.last_error_time = Some(now);
+        }
+    }
+
+    /// Freeze or unfreeze the namespace, or all namespaces of the app if `namespace` is `None`,
+    /// return whether any namespace matched.
+    pub fn set_frozen(&self, app_id: &str, namespace: Option<&str>, frozen: bool) -> bool {
+        let mut namespaces = self.namespaces.lock().unwrap();
+
+        let mut matched = false;
+        for state in find_namespaces(&mut namespaces, app_id, namespace) {
+            state.frozen = frozen;
+            matched = true;
+        }
+        if matched && !frozen {
+            self.unfrozen.send_replace(());
+        }
+        matched
+    }
+
+    pub fn is_frozen(&self, app_id: &str, namespace: &str) -> bool {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        let frozen =
+            find_namespaces(&mut namespaces, app_id, Some(namespace)).any(|state| state.frozen);
+        frozen
+    }
+
+    /// App id and name of the frozen namespaces.
+    pub fn frozen(&self) -> Vec<(String, String)> {
+        self.namespaces
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, state)| state.frozen)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// The last written response of the namespace, `None` if not written yet.
+    pub fn written_response(&self, app_id: &str, namespace: &str) -> Option<FetchResponse> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        let response = find_namespaces(&mut namespaces, app_id, Some(namespace)).find_map(|state| {
+            Some(FetchResponse {
+                app_id: app_id.to_string(),
+                cluster: state.cluster.clone()?,
+                namespace_name: namespace.to_string(),
+                configurations: state.configurations.clone()?,
+                release_key: state.release_key.clone()?,
+            })
+        });
+        response
+    }
+
+    /// Record the newer release held while frozen or pinned.
+    pub fn set_held(&self, app_id: &str, namespace: &str, release_key: &str) {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        for state in find_namespaces(&mut namespaces, app_id, Some(namespace)) {
+            state.held_release_key = Some(release_key.to_string());
+        }
+    }
+
+    /// Receiver notified every time namespaces are unfrozen.
+    pub fn subscribe_unfrozen(&self) -> watch::Receiver<()> {
+        self.unfrozen.subscribe()
+    }
+
+    /// Whether every namespace has been written at least once.
+    pub fn is_ready(&self) -> bool {
+        *self.ready.borrow()
+    }
+
+    /// Wait until every namespace has been written at least once.
+    pub async fn wait_ready(&self) {
+        let mut ready = self.ready.subscribe();
+        while !*ready.borrow() {
+            if ready.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Status of all namespaces.
+    pub fn status(&self) -> Vec<NamespaceStatus> {
+        self.namespaces
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((app_id, namespace), state)| NamespaceStatus {
+                app_id: app_id.clone(),
+                namespace: namespace.clone(),
+                state: state.clone(),
+            })
+            .collect()
+    }
+}
+
+fn find_namespaces<'a>(
+    namespaces: &'a mut BTreeMap<(String, String), NamespaceState>, app_id: &'a str,
+    namespace: Option<&str>,
+) -> impl Iterator<Item = &'a mut NamespaceState> + 'a {
+    let namespace = namespace.map(canonicalize_namespace);
+    namespaces
+        .iter_mut()
+        .filter(move |((id, name), _)| {
+            id == app_id
+                && namespace
+                    .as_ref()
+                    .map(|namespace| &canonicalize_namespace(name) == namespace)
+                    .unwrap_or(true)
+        })
+        .map(|(_, state)| state)
+}
@@ -0,0 +1,162 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Rendering of the [Tera](https://tera.netlify.app/) templates with the pulled configurations.
+//!
+//! The context of a template has:
+//!
+//! - `app_id`: app id of the namespaces.
+//! - `config`: configurations of all namespaces, the latter overrides the former.
+//! - `namespaces`: configurations keyed by the namespace name.
+//!
+//! Besides the builtin filters and functions of Tera, such as `default` and `json_encode`:
+//!
+//! - `value(key, default)` function: look up the key in `config`, error if missing and no
+//!   `default`, convenient for the dotted keys.
+//! - `base64_encode` and `base64_decode` filters.
+
+use crate::{output::write_file_if_changed, state::State};
+use apollo_client::utils::canonicalize_namespace;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
+use tera::{Context, Tera, Value};
+use tokio::fs;
+
+/// Field of config file format.
+#[derive(Deserialize)]
+pub struct TemplateConfig {
+    /// Tera template file, read every rendering.
+    pub template: PathBuf,
+
+    /// Rendered file.
+    pub output: PathBuf,
+
+    /// App id of the namespaces.
+    pub app_id: String,
+
+    /// Namespaces feeding the template, the template is rendered whenever any of them changed.
+    pub namespaces: Vec<String>,
+}
+
+impl TemplateConfig {
+    /// Whether any of the changed namespaces of the app feeds the template.
+    pub fn is_fed_by<'a>(
+        &self, app_id: &str, namespaces: impl IntoIterator<Item = &'a str>,
+    ) -> bool {
+        self.app_id == app_id
+            && namespaces.into_iter().any(|changed| {
+                let changed = canonicalize_namespace(changed);
+                self.namespaces
+                    .iter()
+                    .any(|namespace| canonicalize_namespace(namespace) == changed)
+            })
+    }
+
+    /// Render the template with the last written configurations, skipped if some namespace not
+    /// written yet. Return whether the output file changed.
+    pub async fn render(&self, state: &State) -> anyhow::Result<bool> {
+        let mut config = BTreeMap::new();
+        let mut namespaces = BTreeMap::new();
+        for namespace in &self.namespaces {
+            let configurations = match state.configurations(&self.app_id, namespace) {
+                Some(configurations) => configurations,
+                None => {
+                    log::debug!(
+                        "Namespace {} not written yet, template {} skipped",
+                        namespace,
+                        self.template.display()
+                    );
+                    return Ok(false);
+                }
+            };
+            config.extend(configurations.clone());
+            namespaces.insert(namespace.clone(), configurations);
+        }
+
+        let name = self.template.display().to_string();
+        let source = fs::read_to_string(&self.template).await?;
+
+        let mut tera = Tera::default();
+        tera.add_raw_template(&name, &source)?;
+        tera.register_filter("base64_encode", base64_encode);
+        tera.register_filter("base64_decode", base64_decode);
+        tera.register_function("value", value_function(config.clone()));
+
+        let mut context = Context::new();
+        context.insert("app_id", &self.app_id);
+        context.insert("config", &config);
+        context.insert("namespaces", &namespaces);
+        let content = tera.render(&name, &context)?;
+
+        if let Some(dir) = self.output.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        write_file_if_changed(&self.output, content.as_bytes()).await
+    }
+}
+
+/// Render the templates fed by the changed namespaces of the app, log the failures.
+pub async fn render_changed(
+    templates: &[TemplateConfig], state: &State, app_id: &str, namespaces: &[&str],
+) {
+    for template in templates {
+        if !template.is_fed_by(app_id, namespaces.iter().copied()) {
+            continue;
+        }
+        match template.render(state).await {
+            Ok(true) => log::info!(
+                "Template {} rendered to {}",
+                template.template.display(),
+                template.output.display()
+            ),
+            Ok(false) => {}
+            Err(e) => log::error!(
+                "Render template {} failed: {:?}",
+                template.template.display(),
+                e
+            ),
+        }
+    }
+}
+
+fn value_function(
+    config: BTreeMap<String, String>,
+) -> impl Fn(&HashMap<String, Value>) -> tera::Result<Value> {
+    move |args| {
+        let key = args
+            .get("key")
+            .and_then(Value::as_str)
+            .ok_or_else(|| tera::Error::msg("function `value` requires string argument `key`"))?;
+        match config.get(key) {
+            Some(value) => Ok(Value::String(value.clone())),
+            None => args
+                .get("default")
+                .cloned()
+                .ok_or_else(|| tera::Error::msg(format!("key `{}` not found in config", key))),
+        }
+    }
+}
+
+fn base64_encode(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let value = tera::try_get_value!("base64_encode", "value", String, value);
+    Ok(Value::String(base64::encode(value)))
+}
+
+fn base64_decode(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let value = tera::try_get_value!("base64_decode", "value", String, value);
+    let decoded = base64::decode(value).map_err(tera::Error::msg)?;
+    let decoded = String::from_utf8(decoded).map_err(tera::Error::msg)?;
+    Ok(Value::String(decoded))
+}
@@ -0,0 +1,350 @@
+// Copyright (c) 2021 jmjoy.
+//
+// Apollo Puller is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Watching the namespaces change, like [apollo_client::conf::ApolloConfClient::watch], but the
+//! notification ids and the release keys can be restored, and the failed fetches are retried.
+
+use crate::client::Client;
+use apollo_client::{
+    conf::{
+        meta::{IpValue, Notification},
+        requests::{FetchRequest, NotifyRequest},
+        responses::FetchResponse,
+    },
+    errors::{ApolloClientError, ApolloClientResult},
+    utils::canonicalize_namespace,
+};
+use futures_util::future::{join_all, select_all};
+use prometheus::Histogram;
+use std::{cmp::min, collections::HashMap, time::Duration};
+use tokio::time::{sleep, Instant};
+
+/// Cluster pulled if not configured.
+pub const DEFAULT_CLUSTER: &str = "default";
+
+const MIN_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(64);
+
+/// Notification id of the namespace never notified.
+const UNINITIALIZED_NOTIFICATION_ID: i32 = -1;
+
+struct WatchedNamespace {
+    name: String,
+
+    /// Cluster fallback chain.
+    clusters: Vec<String>,
+
+    /// Notification ids of the clusters.
+    notification_ids: HashMap<String, i32>,
+
+    /// The last fetched response, returned again if apollo responds not modified.
+    response: Option<FetchResponse>,
+
+    /// Whether the namespace should be fetched, because of notified or failed last time.
+    pending: bool,
+}
+
+impl WatchedNamespace {
+    fn notification_id(&self, cluster: &str) -> i32 {
+        self.notification_ids
+            .get(cluster)
+            .copied()
+            .unwrap_or(UNINITIALIZED_NOTIFICATION_ID)
+    }
+}
+
+/// Watcher of the namespaces of an app.
+pub struct Watcher<'a> {
+    client: &'a Client,
+    app_id: String,
+    ip: Option<IpValue>,
+    label: Option<String>,
+    namespaces: Vec<WatchedNamespace>,
+
+    /// Observing the latency of the notification requests answered by apollo.
+    long_poll_latency: Histogram,
+
+    /// Whether to fetch the pending namespaces in the next call.
+    fetch_now: bool,
+
+    /// Whether to sleep before the next notification request.
+    backoff: bool,
+    retry_interval: Duration,
+}
+
+impl<'a> Watcher<'a> {
+    /// `namespaces` are the namespace names and their cluster fallback chains.
+    pub fn new(
+        client: &'a Client, app_id: String, namespaces: Vec<(String, Vec<String>)>,
+        ip: Option<IpValue>, label: Option<String>, long_poll_latency: Histogram,
+    ) -> Self {
+        Self {
+            client,
+            app_id,
+            ip,
+            label,
+            namespaces: namespaces
+                .into_iter()
+                .map(|(name, clusters)| WatchedNamespace {
+                    name,
+                    clusters,
+                    notification_ids: Default::default(),
+                    response: None,
+                    pending: true,
+                })
+                .collect(),
+            long_poll_latency,
+            fetch_now: true,
+            backoff: false,
+            retry_interval: MIN_RETRY_INTERVAL,
+        }
+    }
+
+    /// Restore the notification ids and the response, such as from the local cache, the
+    /// namespace is still fetched but apollo responds not modified if the release not changed.
+    pub fn restore(&mut self, notification_ids: HashMap<String, i32>, response: &FetchResponse) {
+        if let Some(namespace) = self.find_mut(&response.namespace_name) {
+            namespace.notification_ids = notification_ids;
+            namespace.response = Some(clone_response(response));
+        }
+    }
+
+    /// Notification ids of the clusters of the namespace.
+    pub fn notification_ids(&self, namespace: &str) -> HashMap<String, i32> {
+        let namespace = canonicalize_namespace(namespace);
+        self.namespaces
+            .iter()
+            .find(|watched| canonicalize_namespace(&watched.name) == namespace)
+            .map(|watched| watched.notification_ids.clone())
+            .unwrap_or_default()
+    }
+
+    /// Wait for the namespaces changed and fetch them, the first call fetches all namespaces.
+    ///
+    /// Return the error if the notification request failed, the next call will retry after a
+    /// while.
+    ///
+    /// Cancel safe, the notified namespaces are fetched by the next call.
+    pub async fn next(
+        &mut self,
+    ) -> ApolloClientResult<Vec<(String, ApolloClientResult<FetchResponse>)>> {
+        loop {
+            if self.fetch_now {
+                let responses = self.fetch_pending().await;
+                // After fetched, so the pending namespaces are fetched again if cancelled.
+                self.fetch_now = false;
+                if responses.iter().any(|(_, response)| response.is_err()) {
+                    self.backoff = true;
+                }
+                return Ok(responses);
+            }
+
+            if self.backoff {
+                self.backoff = false;
+                sleep(self.retry_interval).await;
+                self.retry_interval = min(self.retry_interval * 2, MAX_RETRY_INTERVAL);
+            }
+
+            let has_pending = self.namespaces.iter().any(|namespace| namespace.pending);
+
+            match self.notify().await {
+                Ok((cluster, notifications)) => {
+                    self.retry_interval = MIN_RETRY_INTERVAL;
+                    for notification in notifications {
+                        if let Some(namespace) = self.find_mut(&notification.namespace_name) {
+                            namespace
+                                .notification_ids
+                                .insert(cluster.clone(), notification.notification_id);
+                            namespace.pending = true;
+                        }
+                    }
+                    self.fetch_now = true;
+                }
+                Err(ApolloClientError::ApolloResponse(e)) if e.status.as_u16() == 304 => {
+                    self.retry_interval = MIN_RETRY_INTERVAL;
+                    // Retry the failed fetches every long polling.
+                    self.fetch_now = has_pending;
+                }
+                Err(e) => {
+                    self.backoff = true;
+                    self.fetch_now = has_pending;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Long poll every cluster watched, return the first notified cluster and its
+    /// notifications.
+    async fn notify(&self) -> ApolloClientResult<(String, Vec<Notification>)> {
+        let mut clusters = Vec::<&str>::new();
+        for namespace in &self.namespaces {
+            for cluster in &namespace.clusters {
+                if !clusters.contains(&&**cluster) {
+                    clusters.push(cluster);
+                }
+            }
+        }
+
+        let notifies = clusters.into_iter().map(|cluster| {
+            Box::pin(async move {
+                let request = NotifyRequest {
+                    app_id: self.app_id.clone(),
+                    cluster_name: cluster.to_string(),
+                    notifications: self
+                        .namespaces
+                        .iter()
+                        .filter(|namespace| namespace.clusters.iter().any(|c| c == cluster))
+                        .map(|namespace| Notification {
+                            namespace_name: namespace.name.clone(),
+                            notification_id: namespace.notification_id(cluster),
+                        })
+                        .collect(),
+                    ..Default::default()
+                };
+                let notifications = self
+                    .client
+                    .notify(request, &label_queries(self.label.as_deref()))
+                    .await?;
+                Ok((cluster.to_string(), notifications))
+            })
+        });
+        let start = Instant::now();
+        let result = select_all(notifies).await.0;
+        if matches!(&result, Ok(_) | Err(ApolloClientError::ApolloResponse(_))) {
+            self.long_poll_latency.observe(start.elapsed().as_secs_f64());
+        }
+        result
+    }
+
+    async fn fetch_pending(&mut self) -> Vec<(String, ApolloClientResult<FetchResponse>)> {
+        let this = &*self;
+        let fetches = this
+            .namespaces
+            .iter()
+            .filter(|namespace| namespace.pending)
+            .map(|namespace| async move {
+                let response = fetch(
+                    this.client,
+                    &this.app_id,
+                    &namespace.name,
+                    &namespace.clusters,
+                    this.ip.clone(),
+                    this.label.clone(),
+                    namespace
+                        .response
+                        .as_ref()
+                        .map(|response| response.release_key.clone()),
+                )
+                .await;
+                (namespace.name.clone(), response)
+            });
+        let responses = join_all(fetches).await;
+
+        let mut results = Vec::with_capacity(responses.len());
+        for (name, response) in responses {
+            let namespace = self.find_mut(&name).unwrap();
+            let response = match response {
+                Ok(response) => {
+                    namespace.response = Some(clone_response(&response));
+                    Ok(response)
+                }
+                Err(ApolloClientError::ApolloResponse(e)) if e.status.as_u16() == 304 => {
+                    // Release not changed.
+                    Ok(clone_response(namespace.response.as_ref().unwrap()))
+                }
+                Err(e) => Err(e),
+            };
+            namespace.pending = response.is_err();
+            results.push((name, response));
+        }
+        results
+    }
+
+    fn find_mut(&mut self, name: &str) -> Option<&mut WatchedNamespace> {
+        let name = canonicalize_namespace(name);
+        self.namespaces
+            .iter_mut()
+            .find(|namespace| canonicalize_namespace(&namespace.name) == name)
+    }
+}
+
+/// Fetch the namespace from the first cluster of the fallback chain having it, that is, apollo
+/// neither responds not found nor falls back to another cluster, the last cluster is used anyway.
+///
+/// Apollo responds not modified if the release of the cluster has the `release_key`.
+pub async fn fetch(
+    client: &Client, app_id: &str, namespace: &str, clusters: &[String], ip: Option<IpValue>,
+    label: Option<String>, release_key: Option<String>,
+) -> ApolloClientResult<FetchResponse> {
+    let (last, fallbacks) = clusters
+        .split_last()
+        .expect("cluster fallback chain is empty");
+
+    for cluster in fallbacks {
+        let result = client
+            .fetch(FetchRequest {
+                app_id: app_id.to_string(),
+                namespace_name: namespace.to_string(),
+                cluster_name: cluster.clone(),
+                ip: ip.clone(),
+                release_key: release_key.clone(),
+                extras_queries: label_queries(label.as_deref()),
+            })
+            .await;
+        match result {
+            Ok(response) if response.cluster != *cluster => {
+                log::debug!(
+                    "Namespace {} not found in cluster {}, fall back to next cluster",
+                    namespace,
+                    cluster
+                );
+            }
+            Err(ApolloClientError::ApolloResponse(e)) if e.status.as_u16() == 404 => {
+                log::debug!(
+                    "Namespace {} not found in cluster {}, fall back to next cluster",
+                    namespace,
+                    cluster
+                );
+            }
+            result => return result,
+        }
+    }
+
+    client
+        .fetch(FetchRequest {
+            app_id: app_id.to_string(),
+            namespace_name: namespace.to_string(),
+            cluster_name: last.clone(),
+            ip,
+            release_key,
+            extras_queries: label_queries(label.as_deref()),
+        })
+        .await
+}
+
+/// Queries matched by the grey releases of apollo, besides the ip.
+fn label_queries(label: Option<&str>) -> Vec<(String, String)> {
+    label
+        .map(|label| vec![("label".to_string(), label.to_string())])
+        .unwrap_or_default()
+}
+
+fn clone_response(response: &FetchResponse) -> FetchResponse {
+    FetchResponse {
+        app_id: response.app_id.clone(),
+        cluster: response.cluster.clone(),
+        namespace_name: response.namespace_name.clone(),
+        configurations: response.configurations.clone(),
+        release_key: response.release_key.clone(),
+    }
+}